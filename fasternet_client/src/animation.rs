@@ -0,0 +1,180 @@
+//! Plays animated images. Each animation keeps track of which frame is
+//! showing and when the next one is due, and a `Ticker` wakes the event loop
+//! when it's time to show it.
+
+use images::Frame;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub struct Animation {
+    frames: Vec<Frame>,
+    current: usize,
+    /// When the next frame is due, `None` while paused
+    next_at: Option<Instant>,
+}
+
+impl Animation {
+    /// Starts paused on the first frame
+    pub fn new(frames: Vec<Frame>) -> Animation {
+        assert!(!frames.is_empty(), "an animation needs frames");
+        Animation { frames, current: 0, next_at: None }
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.frames[self.current]
+    }
+
+    pub fn next_at(&self) -> Option<Instant> {
+        self.next_at
+    }
+
+    /// Stops on the current frame. It's shown for its whole delay again once playing resumes.
+    pub fn pause(&mut self) {
+        self.next_at = None;
+    }
+
+    /// Moves to the frame that should be showing at `now`, starting the clock
+    /// if it was paused. Returns whether the frame changed.
+    pub fn advance(&mut self, now: Instant) -> bool {
+        let mut at = match self.next_at {
+            Some(at) if at <= now => at,
+            Some(_) => return false,
+            None => {
+                self.next_at = Some(now + self.frame().delay);
+                return false;
+            }
+        };
+        // frames missed while the event loop was busy are skipped rather than played late
+        let loop_length: Duration = self.frames.iter().map(|frame| frame.delay).sum();
+        if now.duration_since(at) > loop_length {
+            at = now;
+        }
+        let start = self.current;
+        while at <= now {
+            self.current = (self.current + 1) % self.frames.len();
+            at += self.frame().delay;
+        }
+        self.next_at = Some(at);
+        self.current != start
+    }
+}
+
+/// Wakes the event loop at the earliest time it's asked to
+pub struct Ticker {
+    sender: Sender<Instant>,
+}
+
+impl Ticker {
+    pub fn new(wake: Arc<Fn() + Send + Sync>) -> Ticker {
+        let (sender, deadlines) = mpsc::channel::<Instant>();
+        thread::spawn(move || {
+            let mut deadline: Option<Instant> = None;
+            loop {
+                let next = match deadline {
+                    Some(at) => match deadlines.recv_timeout(at.saturating_duration_since(Instant::now())) {
+                        Ok(next) => next,
+                        Err(RecvTimeoutError::Timeout) => {
+                            deadline = None;
+                            wake();
+                            continue;
+                        }
+                        // the ticker was dropped
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    },
+                    None => match deadlines.recv() {
+                        Ok(next) => next,
+                        Err(_) => return,
+                    },
+                };
+                deadline = Some(deadline.map_or(next, |at| at.min(next)));
+            }
+        });
+        Ticker { sender }
+    }
+
+    /// Wakes the event loop at `at`, or earlier if another wake up was asked for first
+    pub fn wake_at(&self, at: Instant) {
+        // the thread only stops once this is dropped
+        self.sender.send(at).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn animation(delays_ms: &[u64]) -> Animation {
+        Animation::new(delays_ms.iter().map(|&delay| {
+            Frame { bytes: Arc::new(Vec::new()), delay: Duration::from_millis(delay) }
+        }).collect())
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn plays_frames_in_order() {
+        let start = Instant::now();
+        let mut animation = animation(&[100, 50, 200]);
+        assert!(!animation.advance(start));
+        assert_eq!(animation.next_at(), Some(start + ms(100)));
+        assert!(!animation.advance(start + ms(99)));
+        assert!(animation.advance(start + ms(100)));
+        assert_eq!((animation.current, animation.next_at()), (1, Some(start + ms(150))));
+        assert!(animation.advance(start + ms(160)));
+        assert_eq!((animation.current, animation.next_at()), (2, Some(start + ms(350))));
+        // and loops
+        assert!(animation.advance(start + ms(350)));
+        assert_eq!(animation.current, 0);
+    }
+
+    #[test]
+    fn skips_missed_frames() {
+        let start = Instant::now();
+        let mut animation = animation(&[100, 100, 100]);
+        animation.advance(start);
+        assert!(animation.advance(start + ms(250)));
+        assert_eq!((animation.current, animation.next_at()), (2, Some(start + ms(300))));
+
+        // a whole loop late starts timing again from now
+        let late = start + ms(5000);
+        assert!(animation.advance(late));
+        assert_eq!((animation.current, animation.next_at()), (0, Some(late + ms(100))));
+    }
+
+    #[test]
+    fn pause_keeps_frame() {
+        let start = Instant::now();
+        let mut animation = animation(&[100, 100]);
+        animation.advance(start);
+        animation.advance(start + ms(100));
+        animation.pause();
+        assert_eq!(animation.next_at(), None);
+        assert!(!animation.advance(start + ms(1000)));
+        assert_eq!((animation.current, animation.next_at()), (1, Some(start + ms(1100))));
+    }
+
+    #[test]
+    fn ticker_wakes_at_earliest_deadline() {
+        let wakes = Arc::new(AtomicUsize::new(0));
+        let counter = wakes.clone();
+        let ticker = Ticker::new(Arc::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+        let start = Instant::now();
+        ticker.wake_at(start + ms(10_000));
+        ticker.wake_at(start + ms(20));
+
+        let deadline = start + Duration::from_secs(5);
+        while wakes.load(Ordering::SeqCst) == 0 && Instant::now() < deadline {
+            thread::sleep(ms(1));
+        }
+        assert!(start.elapsed() >= ms(20));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // later deadlines are dropped once it wakes, the app asks again for what it still needs
+        thread::sleep(ms(50));
+        assert_eq!(wakes.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,103 +1,608 @@
 use webrender::api::*;
 use glutin;
-use style::{Theme, BuiltTheme, BuiltTextBlock, BuiltBlock, BuiltImageBlock};
-use fasternet_common::{Block};
+use arboard;
+use style::{Theme, BuiltTheme, BuiltBlock, BuiltImageBlock, DocumentMetrics, Highlight, ImageKeys};
+use layout::{self, layout_text, layout_table, plain_block, TextLayout};
+use images::{self, ImageError};
+use animation::{Animation, Ticker};
+use loader::{Loader, LoadedImage};
+use highlight::{Highlighter, HighlightedBlock};
+use budget::TextureBudget;
+use fetch::Cache;
+use selection::{self, Position, Selection, Unit};
+use keys::{self, Action, Keymap};
+use fasternet_common::{Block, Link, TextKind};
 use fasternet_common::markdown::parse_markdown;
+use fasternet_common::outline::outline;
+use fasternet_common::search::Search;
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fs::File;
-use std::collections::HashMap;
-use std::io::Read;
-use std::path::Path;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use rayon::prelude::*;
 
 pub struct App {
+    /// The theme at its own text size, zooming scales it into `built_theme`
+    theme: Theme,
     built_theme: BuiltTheme,
+    zoom: f32,
+    /// Font instances for the text size before zooming, deleted with the next display list
+    retired_fonts: Vec<FontInstanceKey>,
+    /// The parsed document, kept to lay text out again as image sizes become known
+    model: Vec<Block>,
     built_model: Vec<BuiltBlock>,
+    /// Every image of the current document Webrender has, block level or inline
+    image_keys: ImageKeys,
+    /// Sizes of the current document's images, from their headers until they're loaded
+    image_sizes: HashMap<String, (f32, f32)>,
+    /// Decodes images of the current document in the background
+    loader: Option<Loader>,
+    /// Highlights the current document's code blocks in the background
+    highlighter: Option<Highlighter>,
+    /// Images the loader is working on
+    pending: HashSet<String>,
+    /// Images that couldn't be loaded, which aren't tried again
+    failed: HashSet<String>,
+    /// Decides which images to drop from the GPU when they take too much memory
+    budget: TextureBudget,
+    /// Animated images with a texture, and the descriptor to update it with
+    animations: HashMap<String, (ImageDescriptor, Animation)>,
+    animations_paused: bool,
+    /// Wakes the event loop when the next animation frame is due
+    ticker: Ticker,
+    /// Images are decoded at their displayed size times this
+    device_pixel_ratio: f32,
+    /// Y coordinate of the top of each block in `built_model`
+    block_tops: Vec<f32>,
+    doc_path: PathBuf,
     cursor_position: WorldPoint,
     root_clip: ClipId,
+    pipeline_id: PipelineId,
     scroll_offset: LayoutPoint,
+    /// How far code blocks too wide for the column are scrolled sideways, by block index
+    code_scroll: HashMap<usize, f32>,
+    /// Shift turns the mouse wheel sideways
+    shift_held: bool,
+    selection: Option<Selection>,
+    /// The left button is down and dragging moves the end of the selection
+    selecting: bool,
+    /// When and where the last click was and how many came in quick succession before it
+    last_click: Option<(Instant, WorldPoint, u32)>,
+    /// Opened on the first copy and kept open, on X11 copied text is only there while it is
+    clipboard: Option<arboard::Clipboard>,
+    /// Open while the search bar is showing
+    search: Option<Search>,
+    /// Keys type into the search bar
+    search_editing: bool,
+    /// What the keyboard shortcuts do, the defaults changed by the user's `keys.toml`
+    keymap: Keymap,
     total_height: f32,
+    viewport_height: f32,
+    /// Width of the text column, narrower than `WIDTH` if the viewport is small
+    column_width: f32,
+    /// Problems with the current document that didn't stop it from showing
+    diagnostics: Vec<ImageError>,
+    /// Where remote images are fetched through
+    cache: Arc<Cache>,
+    /// Wakes the event loop when an image finishes loading
+    notifier: Box<RenderNotifier>,
 }
 
+/// The widest the column of text gets
 const WIDTH: f32 = 680.0;
-const PADDING: f32 = 20.0;
+/// How far a line of wheel scrolling or an arrow key moves
+const LINE_HEIGHT: f32 = 38.0;
+/// How much each zoom in or out scales the text by
+const ZOOM_STEP: f32 = 1.1;
+const MIN_ZOOM: f32 = 0.5;
+const MAX_ZOOM: f32 = 3.0;
+
+/// How wide the column of text is in a window `viewport_width` wide
+fn column_width(viewport_width: f32) -> f32 {
+    WIDTH.min(viewport_width - 2.0 * layout::PADDING)
+}
+
+#[cfg(target_os = "macos")]
+const DEFAULT_OPENER: &str = "open";
+#[cfg(target_os = "windows")]
+const DEFAULT_OPENER: &str = "explorer";
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+const DEFAULT_OPENER: &str = "xdg-open";
 
 impl App {
-    pub fn new(api: &RenderApi, pipeline_id: PipelineId, path: &str) -> Self {
-        let theme = Theme::new();
+    pub fn new(api: &RenderApi, pipeline_id: PipelineId, path: &str, viewport: LayoutSize, device_pixel_ratio: f32,
+               cache: Cache, notifier: Box<RenderNotifier>) -> io::Result<Self> {
+        let mut theme = match env::var("QUICKDOWN_FONT") {
+            Ok(ref font) if font == "proportional" => Theme::proportional(),
+            _ => Theme::new(),
+        };
+        if let Ok(tab_width) = env::var("QUICKDOWN_TAB_WIDTH") {
+            match tab_width.parse() {
+                Ok(spaces) if spaces > 0 => theme.code_style.tab_width = spaces,
+                _ => println!("ignoring QUICKDOWN_TAB_WIDTH={:?}, it should be a number of spaces", tab_width),
+            }
+        }
         let built_theme = BuiltTheme::new(&theme, api);
-        let model = Self::load_model(&path);
-        let res_folder = Path::new(&path).parent().unwrap();
-
-        let (built_model, total_height) = Self::build_model(&model, &built_theme, &api, WIDTH, &res_folder);
+        let keymap = match keys::config_path() {
+            Some(path) => {
+                let (keymap, errors) = Keymap::load(&path);
+                for err in errors {
+                    println!("warning: ignoring key binding in {}: {}", path.display(), err);
+                }
+                keymap
+            }
+            None => Keymap::default(),
+        };
         let root_clip = ClipId::new(1, pipeline_id);
         let cursor_position = WorldPoint::new(0.0,0.0);
         let scroll_offset = LayoutPoint::zero();
-        App { built_theme, built_model, cursor_position, root_clip, scroll_offset, total_height }
+        let ticker_notifier = Mutex::new(notifier.clone());
+        let ticker = Ticker::new(Arc::new(move || ticker_notifier.lock().unwrap().wake_up()));
+        let mut app = App {
+            theme, built_theme, cursor_position, root_clip, pipeline_id, scroll_offset, notifier, device_pixel_ratio, ticker,
+            keymap,
+            zoom: 1.0,
+            retired_fonts: Vec::new(),
+            code_scroll: HashMap::new(),
+            shift_held: false,
+            selection: None,
+            selecting: false,
+            last_click: None,
+            clipboard: None,
+            search: None,
+            search_editing: false,
+            model: Vec::new(),
+            built_model: Vec::new(),
+            image_keys: ImageKeys::new(),
+            image_sizes: HashMap::new(),
+            loader: None,
+            highlighter: None,
+            pending: HashSet::new(),
+            failed: HashSet::new(),
+            budget: TextureBudget::from_env(),
+            animations: HashMap::new(),
+            animations_paused: false,
+            block_tops: Vec::new(),
+            doc_path: PathBuf::from(path),
+            total_height: 0.0,
+            viewport_height: viewport.height,
+            column_width: column_width(viewport.width),
+            diagnostics: Vec::new(),
+            cache: Arc::new(cache),
+        };
+        app.load_document(api, Path::new(path))?;
+        Ok(app)
+    }
+
+    /// Replaces the current document, freeing the images of the old one. The
+    /// text is laid out right away and images are filled in as they load.
+    fn load_document(&mut self, api: &RenderApi, path: &Path) -> io::Result<()> {
+        let model = Self::load_model(path)?;
+        let res_folder = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let mut updates = ResourceUpdates::new();
+        for (path, key) in self.image_keys.drain() {
+            updates.delete_image(key);
+            self.budget.remove(&path);
+        }
+        self.animations.clear();
+        api.update_resources(updates);
+        self.selection = None;
+        self.selecting = false;
+
+        let mut paths: Vec<String> = model.iter().flat_map(Self::image_paths).map(|path| path.to_string()).collect();
+        paths.sort();
+        paths.dedup();
+
+        // headers are small, so read them now to keep the layout from jumping as images arrive
+        self.image_sizes = paths.par_iter()
+            .filter_map(|path| {
+                images::dimensions(res_folder, path).map(|(w, h)| (path.clone(), (w as f32, h as f32)))
+            })
+            .collect();
+        self.built_model = Self::build_model(&model, &self.built_theme, &self.image_sizes, self.column_width);
+        self.model = model;
+        self.diagnostics = Vec::new();
+        self.restack();
+
+        let notifier = Mutex::new(self.notifier.clone());
+        let wake = Arc::new(move || notifier.lock().unwrap().wake_up());
+        self.loader = Some(Loader::new(res_folder.to_owned(), self.cache.clone(), wake.clone()));
+        let mut highlighter = Highlighter::new(wake);
+        highlighter.highlight(self.model.iter().enumerate().filter_map(|(index, block)| match *block {
+            Block::Text(ref text_block) => {
+                text_block.language.as_ref().map(|language| (index, language.clone(), text_block.content.clone()))
+            }
+            _ => None,
+        }).collect());
+        self.highlighter = Some(highlighter);
+        if let Some(ref mut search) = self.search {
+            search.update(&self.model, 0);
+        }
+        self.pending.clear();
+        self.failed.clear();
+        let widths = self.decode_widths();
+        let requests = paths.into_iter().map(|path| {
+            let width = widths.get(&path).cloned().unwrap_or(u32::MAX);
+            (path, width)
+        }).collect();
+        self.request(requests);
+
+        self.doc_path = path.to_owned();
+        self.scroll_offset = LayoutPoint::zero();
+        Ok(())
     }
 
-    fn build_model(model: &[Block], built_theme: &BuiltTheme, api: &RenderApi, width: f32, res_folder: &Path) -> (Vec<BuiltBlock>, f32) {
-        let mut total_height = 0.0;
-        let mut to_load = Vec::new();
-        let mut built_model: Vec<BuiltBlock> = model.iter().map(|block| {
+    fn request(&mut self, requests: Vec<(String, u32)>) {
+        if requests.is_empty() {
+            return;
+        }
+        self.pending.extend(requests.iter().map(|&(ref path, _)| path.clone()));
+        if let Some(ref mut loader) = self.loader {
+            loader.load(requests);
+        }
+    }
+
+    /// Lays the document out with the image sizes known so far
+    fn build_model(model: &[Block], built_theme: &BuiltTheme, image_sizes: &HashMap<String, (f32, f32)>, width: f32)
+                   -> Vec<BuiltBlock> {
+        let metrics = DocumentMetrics { theme: built_theme, image_sizes };
+        model.iter().map(|block| {
             match *block {
                 Block::Text(ref text_block) => {
-                    let block = BuiltTextBlock::new(text_block, &built_theme, api, width);
-                    total_height += block.size.height + PADDING;
-                    BuiltBlock::Text(block)
+                    BuiltBlock::Text(layout_text(text_block, &metrics, width))
                 },
                 Block::Image(ref image_block) => {
-                    let block = BuiltImageBlock::new(api);
-                    to_load.push((&image_block.path, block.key));
-                    BuiltBlock::Image(block)
+                    let mut built = BuiltImageBlock::new(image_block, built_theme, width);
+                    built.dimensions = image_sizes.get(&image_block.path).map(|&(w, h)| LayoutSize::new(w, h));
+                    BuiltBlock::Image(built)
+                },
+                Block::Table(ref table_block) => {
+                    BuiltBlock::Table(layout_table(table_block, &metrics, width))
                 },
             }
-        }).collect();
+        }).collect()
+    }
 
-        // read all files and decode images (can be in parallel)
-        let to_upload: Vec<(ImageKey, ImageDescriptor, ImageData)> =
-            to_load.par_iter().map(|&(path, key)| {
-                let (descriptor, data) = BuiltImageBlock::load(res_folder, path);
-                (key, descriptor, data)
-        }).collect();
+    /// Every image a block shows, including the ones inline in its text
+    fn image_paths(block: &Block) -> Vec<&str> {
+        match *block {
+            Block::Image(ref image_block) => vec![&image_block.path[..]],
+            Block::Text(ref text_block) => text_block.images.iter().map(|image| &image.path[..]).collect(),
+            Block::Table(ref table_block) => {
+                table_block.header.iter().chain(table_block.rows.iter().flat_map(|row| row.iter()))
+                    .flat_map(|cell| cell.images.iter().map(|image| &image.path[..]))
+                    .collect()
+            }
+        }
+    }
 
-        // patch in all aspect ratios
-        let ratios: HashMap<ImageKey,LayoutSize> = to_upload.iter().map(|&(key, ref descriptor, _)| {
-            (key, LayoutSize::new(descriptor.width as f32, descriptor.height as f32))
-        }).collect();
-        for block in built_model.iter_mut() {
-            if let BuiltBlock::Image(ref mut image_block) = *block {
-                image_block.dimensions = ratios[&image_block.key];
-                total_height += image_block.height(WIDTH) + PADDING;
+    /// Uploads the images that finished loading since the last call,
+    /// returning whether the document needs to be drawn again
+    pub fn poll_images(&mut self, api: &RenderApi) -> bool {
+        let loaded = match self.loader {
+            Some(ref mut loader) => loader.poll(),
+            None => return false,
+        };
+        self.add_images(api, loaded)
+    }
+
+    /// Blocks until every image is loaded and uploaded
+    pub fn wait_for_images(&mut self, api: &RenderApi) {
+        let loaded = match self.loader {
+            Some(ref mut loader) => loader.wait(),
+            None => return,
+        };
+        self.add_images(api, loaded);
+    }
+
+    /// Swaps in the code blocks highlighted since the last call, returning
+    /// whether the document needs to be drawn again
+    pub fn poll_highlights(&mut self) -> bool {
+        let done = match self.highlighter {
+            Some(ref mut highlighter) => highlighter.poll(),
+            None => return false,
+        };
+        self.add_highlights(done)
+    }
+
+    /// Blocks until every code block is highlighted
+    pub fn wait_for_highlights(&mut self) {
+        let done = match self.highlighter {
+            Some(ref mut highlighter) => highlighter.wait(),
+            None => return,
+        };
+        self.add_highlights(done);
+    }
+
+    fn add_highlights(&mut self, done: Vec<HighlightedBlock>) -> bool {
+        if done.is_empty() {
+            return false;
+        }
+        let metrics = DocumentMetrics { theme: &self.built_theme, image_sizes: &self.image_sizes };
+        for HighlightedBlock { index, chunks } in done {
+            if let Block::Text(ref mut text_block) = self.model[index] {
+                text_block.chunks = chunks;
+                self.built_model[index] = BuiltBlock::Text(layout_text(text_block, &metrics, self.column_width));
             }
         }
+        self.restack();
+        true
+    }
+
+    fn add_images(&mut self, api: &RenderApi, loaded: Vec<LoadedImage>) -> bool {
+        if loaded.is_empty() {
+            return false;
+        }
 
-        // upload all the images to Webrender
         let mut updates = ResourceUpdates::new();
-        for (key, descriptor, data) in to_upload.into_iter() {
-            updates.add_image(key, descriptor, data, None);
+        let mut resized = false;
+        for LoadedImage { path, result } in loaded {
+            self.pending.remove(&path);
+            match result {
+                Ok(image) => {
+                    let size = (image.natural_width as f32, image.natural_height as f32);
+                    let frame_bytes: usize = image.frames.iter().map(|frame| frame.bytes.len()).sum();
+                    self.budget.insert(&path, image.bytes.len() + frame_bytes);
+                    let descriptor = BuiltImageBlock::descriptor(&image);
+                    if image.frames.is_empty() {
+                        self.animations.remove(&path);
+                    } else {
+                        self.animations.insert(path.clone(), (descriptor, Animation::new(image.frames)));
+                    }
+                    let data = ImageData::new(image.bytes);
+                    // images decoded again for a new pixel ratio keep their key
+                    let key = match self.image_keys.get(&path) {
+                        Some(&key) => {
+                            updates.update_image(key, descriptor, data, None);
+                            key
+                        }
+                        None => {
+                            let key = api.generate_image_key();
+                            updates.add_image(key, descriptor, data, None);
+                            key
+                        }
+                    };
+                    for image_block in Self::image_blocks(&mut self.built_model, &path) {
+                        image_block.key = Some(key);
+                        image_block.dimensions = Some(LayoutSize::new(size.0, size.1));
+                    }
+                    self.image_keys.insert(path.clone(), key);
+                    resized |= self.image_sizes.insert(path, size) != Some(size);
+                }
+                Err(err) => {
+                    println!("warning: {}", err);
+                    self.failed.insert(path.clone());
+                    for image_block in Self::image_blocks(&mut self.built_model, &path) {
+                        image_block.fail(&err, &self.built_theme, self.column_width);
+                    }
+                    self.diagnostics.push(err);
+                }
+            }
         }
+
+        // only images the headers didn't tell us about move the text around
+        if resized {
+            self.relayout_text();
+        }
+        self.restack();
+        self.evict(&mut updates);
         api.update_resources(updates);
+        true
+    }
+
+    /// Drops the textures of images far off-screen while they take more memory than the budget allows
+    fn evict(&mut self, updates: &mut ResourceUpdates) {
+        let distances = self.image_distances();
+        for path in self.budget.evictions(&distances, self.viewport_height) {
+            self.budget.remove(&path);
+            self.animations.remove(&path);
+            if let Some(key) = self.image_keys.remove(&path) {
+                updates.delete_image(key);
+            }
+            for image_block in Self::image_blocks(&mut self.built_model, &path) {
+                image_block.key = None;
+            }
+        }
+    }
+
+    /// Loads dropped images again when they're within a screen of the viewport
+    fn reload_near_viewport(&mut self) {
+        let widths = self.decode_widths();
+        let requests = self.image_distances().into_iter()
+            .filter(|&(ref path, distance)| {
+                distance <= self.viewport_height && !self.image_keys.contains_key(path)
+                    && !self.pending.contains(path) && !self.failed.contains(path)
+            })
+            .map(|(path, _)| {
+                let width = widths.get(&path).cloned().unwrap_or(u32::MAX);
+                (path, width)
+            })
+            .collect();
+        self.request(requests);
+    }
+
+    /// Shows the next frame of each animation on screen that's due, and sets
+    /// the ticker for the one after. Animations off-screen are paused. Returns
+    /// whether the document needs to be drawn again.
+    pub fn animate(&mut self, api: &RenderApi) -> bool {
+        if self.animations.is_empty() {
+            return false;
+        }
+        let now = Instant::now();
+        let distances = self.image_distances();
+        let mut updates = ResourceUpdates::new();
+        let mut changed = false;
+        let mut next_at: Option<Instant> = None;
+        for (path, &mut (descriptor, ref mut animation)) in &mut self.animations {
+            if self.animations_paused || distances.get(path) != Some(&0.0) {
+                animation.pause();
+                continue;
+            }
+            if animation.advance(now) {
+                let data = ImageData::new_shared(animation.frame().bytes.clone());
+                updates.update_image(self.image_keys[path], descriptor, data, None);
+                changed = true;
+            }
+            next_at = match (next_at, animation.next_at()) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+        }
+        if let Some(at) = next_at {
+            self.ticker.wake_at(at);
+        }
+        if changed {
+            api.update_resources(updates);
+        }
+        changed
+    }
+
+    pub fn set_texture_budget(&mut self, budget: TextureBudget) {
+        self.budget = budget;
+    }
+
+    /// Decodes the loaded images again at the right resolution when the window
+    /// moves to a screen with a different pixel ratio
+    pub fn set_device_pixel_ratio(&mut self, device_pixel_ratio: f32) {
+        if device_pixel_ratio == self.device_pixel_ratio {
+            return;
+        }
+        self.device_pixel_ratio = device_pixel_ratio;
+        self.redecode_loaded();
+    }
+
+    /// Decodes the loaded images again at the size they're drawn at now, they
+    /// keep showing the old decode until the new one arrives
+    fn redecode_loaded(&mut self) {
+        let widths = self.decode_widths();
+        let requests = self.image_keys.keys()
+            .filter(|path| !self.pending.contains(*path))
+            .map(|path| (path.clone(), widths.get(path).cloned().unwrap_or(u32::MAX)))
+            .collect();
+        self.request(requests);
+    }
 
-        (built_model, total_height)
+    /// Every place an image is shown: its path, top and bottom in document
+    /// coordinates, and how wide it's drawn
+    fn image_extents(&self) -> Vec<(&str, f32, f32, f32)> {
+        let mut extents = Vec::new();
+        for (block, &top) in self.built_model.iter().zip(&self.block_tops) {
+            match *block {
+                BuiltBlock::Text(ref text) => self.atom_extents(text, top, &mut extents),
+                BuiltBlock::Image(ref image_block) => {
+                    let bottom = top + image_block.height(self.column_width);
+                    let width = image_block.display_size(self.column_width)
+                        .map_or(self.column_width - image_block.indent(), |size| size.width);
+                    extents.push((&image_block.source.path[..], top, bottom, width));
+                }
+                BuiltBlock::Table(ref table) => {
+                    for (cell, _, y) in table.cells() {
+                        self.atom_extents(cell, top + y, &mut extents);
+                    }
+                }
+            }
+        }
+        extents
+    }
+
+    fn atom_extents<'a>(&self, text: &'a TextLayout, top: f32, extents: &mut Vec<(&'a str, f32, f32, f32)>) {
+        for atom in &text.atoms {
+            // until its size is known an image could end up as wide as the column
+            let width = if atom.sized { atom.width } else { self.column_width };
+            extents.push((&atom.path[..], top + atom.y, top + atom.y + atom.height, width));
+        }
     }
 
-    fn load_model(path: &str) -> Vec<Block> {
-        let mut f = File::open(path).unwrap();
+    /// How many pixels wide each image should be decoded, the widest it's drawn on this screen
+    fn decode_widths(&self) -> HashMap<String, u32> {
+        let mut widths = HashMap::new();
+        for (path, _, _, width) in self.image_extents() {
+            let pixels = (width * self.device_pixel_ratio).ceil() as u32;
+            let widest = widths.entry(path.to_string()).or_insert(0);
+            *widest = pixels.max(*widest);
+        }
+        widths
+    }
+
+    /// How far each image is from the viewport, 0 if any of it is on screen
+    fn image_distances(&self) -> HashMap<String, f32> {
+        let (view_top, view_bottom) = (self.scroll_offset.y, self.scroll_offset.y + self.viewport_height);
+        let mut distances = HashMap::new();
+        for (path, top, bottom, _) in self.image_extents() {
+            let distance = (top - view_bottom).max(view_top - bottom).max(0.0);
+            let closest = distances.entry(path.to_string()).or_insert(distance);
+            *closest = distance.min(*closest);
+        }
+        distances
+    }
+
+    /// The block level images showing `path`
+    fn image_blocks<'a>(built_model: &'a mut [BuiltBlock], path: &'a str)
+                        -> impl Iterator<Item = &'a mut BuiltImageBlock> + 'a {
+        built_model.iter_mut().filter_map(move |block| match *block {
+            BuiltBlock::Image(ref mut image_block) if image_block.source.path == path => Some(image_block),
+            _ => None,
+        })
+    }
+
+    /// Lays text and tables out again for the current image sizes, leaving image blocks be
+    fn relayout_text(&mut self) {
+        let metrics = DocumentMetrics { theme: &self.built_theme, image_sizes: &self.image_sizes };
+        for (block, built) in self.model.iter().zip(self.built_model.iter_mut()) {
+            match *block {
+                Block::Text(ref text_block) => {
+                    *built = BuiltBlock::Text(layout_text(text_block, &metrics, self.column_width));
+                }
+                Block::Table(ref table_block) => {
+                    *built = BuiltBlock::Table(layout_table(table_block, &metrics, self.column_width));
+                }
+                Block::Image(_) => (),
+            }
+        }
+    }
+
+    fn restack(&mut self) {
+        let (block_tops, total_height) = Self::layout_blocks(&self.built_model, self.column_width);
+        self.block_tops = block_tops;
+        self.total_height = total_height;
+    }
+
+    /// Stacks the blocks vertically, returning the top of each and the total height
+    fn layout_blocks(built_model: &[BuiltBlock], width: f32) -> (Vec<f32>, f32) {
+        let heights: Vec<(f32, bool)> = built_model.iter()
+            .map(|block| (block.height(width), block.in_list()))
+            .collect();
+        layout::stack_blocks(&heights)
+    }
+
+    fn load_model(path: &Path) -> io::Result<Vec<Block>> {
+        let mut f = File::open(path)?;
         // let mut f = File::open("Readme.md").unwrap();
         let mut buffer = String::new();
-        f.read_to_string(&mut buffer).unwrap();
+        f.read_to_string(&mut buffer)?;
 
-        parse_markdown(&buffer)
+        Ok(parse_markdown(&buffer))
     }
 
     pub fn render(&mut self,
               _api: &RenderApi,
               builder: &mut DisplayListBuilder,
-              _resources: &mut ResourceUpdates,
+              resources: &mut ResourceUpdates,
               layout_size: LayoutSize,
               _pipeline_id: PipelineId,
               _document_id: DocumentId) {
         println!("rendering at size {:?}", layout_size);
+        self.viewport_height = layout_size.height;
+        // the display list this replaces was the last to use them
+        for font_instance in self.retired_fonts.drain(..) {
+            resources.delete_font_instance(font_instance);
+        }
 
         let bounds = LayoutRect::new(LayoutPoint::zero(), layout_size);
         builder.push_stacking_context(&PrimitiveInfo::new(bounds),
@@ -107,7 +612,7 @@ impl App {
                                       None,
                                       MixBlendMode::Normal,
                                       Vec::new());
-        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(WIDTH, self.total_height));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(layout_size.width, self.total_height));
         builder.define_scroll_frame(
             Some(self.root_clip),
             content_rect,
@@ -117,56 +622,396 @@ impl App {
             ScrollSensitivity::ScriptAndInputEvents);
         builder.push_clip_id(self.root_clip);
 
-        let x = (layout_size.width - WIDTH) / 2.0;
-        let mut y = 10.0;
-        for block in &self.built_model {
+        let x = self.left_edge(layout_size);
+        for (index, (block, &y)) in self.built_model.iter().zip(&self.block_tops).enumerate() {
             match *block {
-                BuiltBlock::Text(ref text_block) => {
-                    text_block.draw(builder, LayoutPoint::new(x, y));
-                    y += text_block.size.height + PADDING;
+                BuiltBlock::Text(ref text) => {
+                    let mut highlights: Vec<_> = self.search_highlights(index).into_iter()
+                        .map(|(_, range, highlight)| (range, highlight))
+                        .collect();
+                    if let Some(range) = self.selection.as_ref().and_then(|selection| selection.block_range(index, text.text_len)) {
+                        highlights.push((range, Highlight::Selection));
+                    }
+                    let frame = if text.scroll_width() > 0.0 { Some(self.code_scroll_frame(index)) } else { None };
+                    self.built_theme.draw_document_text(builder, text, &self.image_keys, LayoutPoint::new(x, y), &highlights, frame);
                 }
                 BuiltBlock::Image(ref image_block) =>  {
-                    image_block.draw(builder, LayoutPoint::new(x, y), WIDTH);
-                    y += image_block.height(WIDTH) + PADDING;
+                    let origin = LayoutPoint::new(x, y);
+                    self.built_theme.draw_image(builder, image_block, &self.image_keys, origin, self.column_width);
                 },
+                BuiltBlock::Table(ref table) => {
+                    let origin = LayoutPoint::new(x, y);
+                    self.built_theme.draw_table(builder, table, &self.image_keys, origin);
+                    let cells = table.cells();
+                    for (cell, range, highlight) in self.search_highlights(index) {
+                        if let Some(&(cell, cell_x, cell_y)) = cell.and_then(|cell| cells.get(cell)) {
+                            let cell_origin = origin + LayoutVector2D::new(cell_x, cell_y);
+                            self.built_theme.draw_highlights(builder, cell, cell_origin, &[(range, highlight)]);
+                        }
+                    }
+                }
+            }
+            // images and tables are selected whole
+            let whole_block_selected = match *block {
+                BuiltBlock::Text(_) => false,
+                _ => self.selection.as_ref().and_then(|selection| selection.block_range(index, 1)).is_some(),
+            };
+            if whole_block_selected {
+                let width = match *block {
+                    BuiltBlock::Table(ref table) => table.width,
+                    _ => self.column_width - block.indent(),
+                };
+                let rect = LayoutRect::new(LayoutPoint::new(x + block.indent(), y), LayoutSize::new(width, block.height(self.column_width)));
+                self.built_theme.draw_highlight(builder, rect, Highlight::Selection);
             }
         }
-
         builder.pop_clip_id();
+
+        // the search bar stays put over the document as it scrolls
+        if let Some(ref search) = self.search {
+            let label = if search.regex { "Regex: " } else { "Find: " };
+            let query = plain_block(format!("{}{}", label, search.query), TextKind::Paragraph);
+            let status = match (&search.error, search.active) {
+                (&Some(_), _) => "invalid pattern".to_string(),
+                (_, Some(active)) => format!("{} of {}", active + 1, search.matches.len()),
+                _ if search.query.is_empty() => String::new(),
+                _ => "no matches".to_string(),
+            };
+            let query = layout_text(&query, &self.built_theme, f32::INFINITY);
+            let status = layout_text(&plain_block(status, TextKind::Caption), &self.built_theme, f32::INFINITY);
+            self.built_theme.draw_search_bar(builder, &query, &status, self.search_editing, layout_size.width);
+        }
         builder.pop_stacking_context();
     }
 
+    /// The search matches in a block as the table cell they're in if it's a
+    /// table, the range of text and how they're highlighted
+    fn search_highlights(&self, index: usize) -> Vec<(Option<usize>, Range<usize>, Highlight)> {
+        let search = match self.search {
+            Some(ref search) => search,
+            None => return Vec::new(),
+        };
+        let (first, matches) = search.block_matches(index);
+        matches.iter().enumerate().map(|(i, found)| {
+            let highlight = if search.active == Some(first + i) { Highlight::ActiveMatch } else { Highlight::SearchMatch };
+            (found.cell, found.range.clone(), highlight)
+        }).collect()
+    }
+
+    /// The shortcut a key is bound to, unless the search bar wants the key.
+    /// Shortcuts the app can't carry out itself, like quitting, are left to
+    /// the window through this.
+    pub fn key_action(&self, key: glutin::VirtualKeyCode, modifiers: glutin::ModifiersState) -> Option<Action> {
+        if self.search_takes(key) {
+            None
+        } else {
+            self.keymap.action(key, modifiers)
+        }
+    }
+
+    /// While typing into the search bar it gets every key, otherwise just the
+    /// ones to step through matches and close it
+    fn search_takes(&self, key: glutin::VirtualKeyCode) -> bool {
+        use glutin::VirtualKeyCode::*;
+        self.search_editing || (self.search.is_some() && (key == Escape || key == N))
+    }
+
+    /// Handles keys for the search bar, returning `None` for keys it doesn't
+    /// want or whether the document needs rendering again
+    fn on_search_key(&mut self, key: glutin::VirtualKeyCode, modifiers: glutin::ModifiersState,
+                     api: &RenderApi, document_id: DocumentId) -> Option<bool> {
+        use glutin::VirtualKeyCode::*;
+        if !self.search_takes(key) {
+            return None;
+        }
+        match key {
+            Escape => {
+                self.search = None;
+                self.search_editing = false;
+            }
+            Back if self.search_editing => {
+                if let Some(ref mut search) = self.search {
+                    search.query.pop();
+                }
+                self.update_search(api, document_id);
+            }
+            Tab if self.search_editing => {
+                if let Some(ref mut search) = self.search {
+                    search.regex = !search.regex;
+                }
+                self.update_search(api, document_id);
+            }
+            Return | NumpadEnter if self.search_editing => self.search_editing = false,
+            N if !self.search_editing => {
+                if let Some(ref mut search) = self.search {
+                    search.step(!modifiers.shift);
+                }
+                self.reveal_active_match(api, document_id);
+            }
+            // everything else is typed, see `ReceivedCharacter`
+            _ => return Some(false),
+        }
+        Some(true)
+    }
+
+    /// Carries out a shortcut, returning whether the document needs rendering again
+    fn perform(&mut self, action: Action, api: &RenderApi, document_id: DocumentId) -> bool {
+        match action {
+            Action::PauseAnimations => self.animations_paused = !self.animations_paused,
+            Action::Copy => self.copy_selection(false),
+            Action::CopyMarkdown => self.copy_selection(true),
+            Action::Find => {
+                if self.search.is_none() {
+                    self.search = Some(Search::default());
+                }
+                self.search_editing = true;
+                return true;
+            }
+            Action::LineUp => self.scroll_by(LINE_HEIGHT, api, document_id),
+            Action::LineDown => self.scroll_by(-LINE_HEIGHT, api, document_id),
+            Action::PageUp => self.scroll_by(self.page_height(), api, document_id),
+            Action::PageDown => self.scroll_by(-self.page_height(), api, document_id),
+            Action::Top => self.scroll_clamped(0.0, api, document_id),
+            Action::Bottom => self.scroll_clamped(self.total_height, api, document_id),
+            Action::PreviousHeading => {
+                let above = self.heading_tops().into_iter().rev().find(|&top| top < self.scroll_offset.y - 1.0);
+                self.scroll_clamped(above.unwrap_or(0.0), api, document_id);
+            }
+            Action::NextHeading => {
+                if let Some(below) = self.heading_tops().into_iter().find(|&top| top > self.scroll_offset.y + 1.0) {
+                    self.scroll_clamped(below, api, document_id);
+                }
+            }
+            Action::Reload => return self.reload(api, document_id),
+            Action::ZoomIn => return self.set_zoom(self.zoom * ZOOM_STEP, api, document_id),
+            Action::ZoomOut => return self.set_zoom(self.zoom / ZOOM_STEP, api, document_id),
+            Action::ResetZoom => return self.set_zoom(1.0, api, document_id),
+            // the window handles these
+            Action::Quit | Action::ToggleProfiler => (),
+        }
+        false
+    }
+
+    /// Reads the document from disk again, keeping the scroll position
+    fn reload(&mut self, api: &RenderApi, document_id: DocumentId) -> bool {
+        let path = self.doc_path.clone();
+        let y = self.scroll_offset.y;
+        if let Err(err) = self.load_document(api, &path) {
+            println!("couldn't reload {}: {}", path.display(), err);
+            return false;
+        }
+        self.reset_code_scroll(api, document_id);
+        self.scroll_clamped(y, api, document_id);
+        true
+    }
+
+    /// Scales the text and images, keeping the same part of the document in view
+    fn set_zoom(&mut self, zoom: f32, api: &RenderApi, document_id: DocumentId) -> bool {
+        let zoom = zoom.clamp(MIN_ZOOM, MAX_ZOOM);
+        if (zoom - self.zoom).abs() < 0.001 {
+            return false;
+        }
+        self.zoom = zoom;
+        let retired = self.built_theme.set_zoom(&self.theme, zoom, api);
+        self.retired_fonts.extend(retired);
+        self.relayout_all(api, document_id);
+        true
+    }
+
+    /// Fits the column to the window after it was resized
+    fn resize(&mut self, viewport: LayoutSize, api: &RenderApi, document_id: DocumentId) {
+        self.viewport_height = viewport.height;
+        let column_width = column_width(viewport.width);
+        if column_width != self.column_width {
+            self.column_width = column_width;
+            self.relayout_all(api, document_id);
+        }
+    }
+
+    /// Lays every block out again after the text size or column width
+    /// changed, keeping the same part of the document in view
+    fn relayout_all(&mut self, api: &RenderApi, document_id: DocumentId) {
+        let position = if self.total_height > 0.0 { self.scroll_offset.y / self.total_height } else { 0.0 };
+        self.relayout_text();
+        for built in &mut self.built_model {
+            if let BuiltBlock::Image(ref mut image_block) = *built {
+                image_block.relayout(&self.built_theme, self.column_width);
+            }
+        }
+        self.restack();
+        self.redecode_loaded();
+        self.reset_code_scroll(api, document_id);
+        self.scroll_clamped(position * self.total_height, api, document_id);
+    }
+
+    /// How far paging moves, keeping a line of the last page in view
+    fn page_height(&self) -> f32 {
+        (self.viewport_height - LINE_HEIGHT).max(LINE_HEIGHT)
+    }
+
+    fn heading_tops(&self) -> Vec<f32> {
+        outline(&self.model).iter().map(|entry| self.block_tops[entry.block]).collect()
+    }
+
+    /// Searches again after the query changed, starting from the top of the window
+    fn update_search(&mut self, api: &RenderApi, document_id: DocumentId) {
+        let from = self.built_model.iter().zip(&self.block_tops)
+            .position(|(block, &top)| top + block.height(self.column_width) >= self.scroll_offset.y)
+            .unwrap_or(0);
+        if let Some(ref mut search) = self.search {
+            search.update(&self.model, from);
+        }
+        self.reveal_active_match(api, document_id);
+    }
+
+    /// Scrolls the current match into view if it's not, sideways too if it's in a wide code block
+    fn reveal_active_match(&mut self, api: &RenderApi, document_id: DocumentId) {
+        let found = match self.search.as_ref().and_then(|search| search.active_match()) {
+            Some(found) => found.clone(),
+            None => return,
+        };
+        let rect = match self.built_model[found.block] {
+            BuiltBlock::Text(ref text) => text.range_rects(found.range.clone()).first().cloned(),
+            BuiltBlock::Table(ref table) => table.cells().get(found.cell.unwrap_or(0)).and_then(|&(cell, cell_x, cell_y)| {
+                cell.range_rects(found.range.clone()).first().map(|&(x, y, width, height)| (cell_x + x, cell_y + y, width, height))
+            }),
+            BuiltBlock::Image(_) => None,
+        };
+        let (x, y, width, height) = match rect {
+            Some(rect) => rect,
+            None => return,
+        };
+
+        let top = self.block_tops[found.block] + y;
+        let visible_top = self.scroll_offset.y + self.built_theme.search_bar_bottom();
+        if top < visible_top || top + height > self.scroll_offset.y + self.viewport_height {
+            self.scroll_clamped(top - self.viewport_height / 3.0, api, document_id);
+        }
+
+        let sideways = match self.built_model[found.block] {
+            BuiltBlock::Text(ref text) if text.scroll_width() > 0.0 => {
+                let scrolled = self.code_scroll.get(&found.block).cloned().unwrap_or(0.0);
+                let visible = text.width - text.indent;
+                let x = x - text.indent;
+                if x < scrolled || x + width > scrolled + visible {
+                    Some((x - visible / 3.0).clamp(0.0, text.scroll_width()))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+        if let Some(code_x) = sideways {
+            self.set_code_scroll(found.block, code_x, api, document_id);
+        }
+    }
+
     pub fn on_event(&mut self,
                 event: glutin::WindowEvent,
                 api: &RenderApi,
                 layout_size: LayoutSize,
                 document_id: DocumentId) -> bool {
         match event {
-            glutin::WindowEvent::Resized(_w, _h) => return true,
+            glutin::WindowEvent::KeyboardInput {
+                input: glutin::KeyboardInput {
+                    virtual_keycode: Some(key),
+                    state: glutin::ElementState::Pressed,
+                    modifiers, ..
+                }, ..
+            } => {
+                if let Some(needs_render) = self.on_search_key(key, modifiers, api, document_id) {
+                    return needs_render;
+                }
+                if let Some(action) = self.keymap.action(key, modifiers) {
+                    return self.perform(action, api, document_id);
+                }
+            }
+            glutin::WindowEvent::ReceivedCharacter(c) if self.search_editing => {
+                if c.is_control() {
+                    return false;
+                }
+                if let Some(ref mut search) = self.search {
+                    search.query.push(c);
+                }
+                self.update_search(api, document_id);
+                return true;
+            }
+            _ => (),
+        }
+
+        match event {
+            glutin::WindowEvent::Resized(..) => {
+                self.resize(layout_size, api, document_id);
+                return true;
+            }
             glutin::WindowEvent::MouseWheel { device_id: _, delta, phase: _ } => {
-                const LINE_HEIGHT: f32 = 38.0;
-                let (_dx, dy) = match delta {
-                    glutin::MouseScrollDelta::LineDelta(dx, dy) => (dx, dy * LINE_HEIGHT),
+                let (dx, dy) = match delta {
+                    glutin::MouseScrollDelta::LineDelta(dx, dy) => (dx * LINE_HEIGHT, dy * LINE_HEIGHT),
                     glutin::MouseScrollDelta::PixelDelta(dx, dy) => (dx, dy),
                 };
+                let (dx, dy) = if self.shift_held { (dy, dx) } else { (dx, dy) };
+
+                if dx.abs() > dy.abs() {
+                    // only wide code blocks scroll sideways, the one under the cursor
+                    let point = self.cursor_in_document();
+                    if let Some(index) = self.wide_code_at(point) {
+                        self.scroll_code(index, dx, api, document_id);
+                    }
+                    return false;
+                }
 
                 // let scroll_states = api.get_scroll_node_state(document_id);
                 // let state = scroll_states.iter().find(|l| l.id == self.root_clip).unwrap();
                 // let cur_offset = state.scroll_offset;
-                self.scroll_offset += LayoutVector2D::new(0.0, -dy);
-                let max_y = self.total_height - layout_size.height;
-                if self.scroll_offset.y < 0.0 {
-                    self.scroll_offset.y = 0.0;
-                } else if self.scroll_offset.y > max_y {
-                    self.scroll_offset.y = max_y;
-                }
-                self.scroll_offset.y = self.scroll_offset.y.round();
-
-                api.scroll_node_with_id(document_id, self.scroll_offset,
-                    self.root_clip, ScrollClamping::NoClamping);
+                self.scroll_by(dy, api, document_id);
             },
+            glutin::WindowEvent::KeyboardInput {
+                input: glutin::KeyboardInput { virtual_keycode: Some(key), state, .. }, ..
+            } if key == glutin::VirtualKeyCode::LShift || key == glutin::VirtualKeyCode::RShift => {
+                self.shift_held = state == glutin::ElementState::Pressed;
+            }
             glutin::WindowEvent::CursorMoved { device_id: _, position: (x,y) } => {
                 self.cursor_position = WorldPoint::new(x as f32, y as f32);
+                if self.selecting {
+                    let to = self.position_at(self.cursor_in_document(), layout_size);
+                    if let (Some(to), Some(selection)) = (to, self.selection.as_mut()) {
+                        return selection.extend(&self.model, to);
+                    }
+                }
+            }
+            glutin::WindowEvent::MouseInput {
+                state: glutin::ElementState::Pressed,
+                button: glutin::MouseButton::Left, ..
+            } => {
+                let unit = match self.count_click() {
+                    1 => Unit::Char,
+                    2 => Unit::Word,
+                    _ => Unit::Block,
+                };
+                let had_selection = self.selection.take().is_some();
+                if let Some(at) = self.position_at(self.cursor_in_document(), layout_size) {
+                    self.selection = Some(Selection::new(&self.model, at, unit));
+                    self.selecting = true;
+                }
+                return had_selection || self.selection.is_some();
+            }
+            glutin::WindowEvent::MouseInput {
+                state: glutin::ElementState::Released,
+                button: glutin::MouseButton::Left, ..
+            } => {
+                self.selecting = false;
+                let dragged = match self.selection {
+                    Some(ref selection) => !selection.is_empty(),
+                    None => false,
+                };
+                if dragged {
+                    return false;
+                }
+                self.selection = None;
+                let dest = self.link_at(self.cursor_in_document(), layout_size).map(|link| link.dest.clone());
+                if let Some(dest) = dest {
+                    return self.follow_link(&dest, api, document_id);
+                }
             }
             _ => ()
         }
@@ -174,6 +1019,187 @@ impl App {
         false
     }
 
+    fn left_edge(&self, layout_size: LayoutSize) -> f32 {
+        (layout_size.width - self.column_width) / 2.0
+    }
+
+    fn cursor_in_document(&self) -> LayoutPoint {
+        LayoutPoint::new(self.cursor_position.x, self.cursor_position.y + self.scroll_offset.y)
+    }
+
+    /// Counts the clicks in quick succession at about the same spot, ending in this one, up to a triple click
+    fn count_click(&mut self) -> u32 {
+        const MULTI_CLICK_TIME: Duration = Duration::from_millis(400);
+        let now = Instant::now();
+        let count = match self.last_click {
+            Some((at, point, count)) if now - at < MULTI_CLICK_TIME && (point - self.cursor_position).length() < 4.0 => {
+                count % 3 + 1
+            }
+            _ => 1,
+        };
+        self.last_click = Some((now, self.cursor_position, count));
+        count
+    }
+
+    /// Where a selection dragged to a point in document coordinates starts or ends
+    fn position_at(&self, point: LayoutPoint, layout_size: LayoutSize) -> Option<Position> {
+        // the block the point is over, or the one above the gap it's in
+        let index = self.block_tops.iter().rposition(|&top| top <= point.y).unwrap_or(0);
+        let block = self.built_model.get(index)?;
+        let top = self.block_tops[index];
+        let byte = match *block {
+            BuiltBlock::Text(ref text) => {
+                let scrolled = if text.scroll_width() > 0.0 { self.code_scroll.get(&index).cloned().unwrap_or(0.0) } else { 0.0 };
+                text.position_at(point.x - self.left_edge(layout_size) + scrolled, point.y - top)
+            }
+            _ if point.y < top + block.height(self.column_width) / 2.0 => 0,
+            _ => 1,
+        };
+        Some(Position { block: index, byte })
+    }
+
+    /// Copies the selected text to the clipboard, as Markdown if asked to
+    fn copy_selection(&mut self, as_markdown: bool) {
+        let range = match self.selection {
+            Some(ref selection) if !selection.is_empty() => selection.range(),
+            _ => return,
+        };
+        let text = if as_markdown {
+            selection::markdown(&self.model, range)
+        } else {
+            selection::plain_text(&self.model, range)
+        };
+        if self.clipboard.is_none() {
+            match arboard::Clipboard::new() {
+                Ok(clipboard) => self.clipboard = Some(clipboard),
+                Err(err) => {
+                    println!("couldn't open the clipboard: {}", err);
+                    return;
+                }
+            }
+        }
+        if let Err(err) = self.clipboard.as_mut().unwrap().set_text(text) {
+            println!("couldn't copy to the clipboard: {}", err);
+        }
+    }
+
+    /// Moves the top of the viewport to `y` in document coordinates
+    pub fn scroll_to(&mut self, y: f32, api: &RenderApi, document_id: DocumentId) {
+        self.scroll_offset = LayoutPoint::new(0.0, y);
+        api.scroll_node_with_id(document_id, self.scroll_offset,
+            self.root_clip, ScrollClamping::NoClamping);
+        self.reload_near_viewport();
+    }
+
+    /// Scrolls to `y`, kept between the top and where the end of the
+    /// document reaches the bottom of the window
+    fn scroll_clamped(&mut self, y: f32, api: &RenderApi, document_id: DocumentId) {
+        let max_y = (self.total_height - self.viewport_height).max(0.0);
+        self.scroll_to(y.clamp(0.0, max_y).round(), api, document_id);
+    }
+
+    /// Scrolls the way the wheel does, a positive `dy` moves towards the top
+    fn scroll_by(&mut self, dy: f32, api: &RenderApi, document_id: DocumentId) {
+        let y = self.scroll_offset.y - dy;
+        self.scroll_clamped(y, api, document_id);
+    }
+
+    /// The scroll frame of a code block too wide for the column, the root frame is 1
+    fn code_scroll_frame(&self, index: usize) -> ClipId {
+        ClipId::new(index as u64 + 2, self.pipeline_id)
+    }
+
+    /// Finds the code block under a point in document coordinates, if it's wide enough to scroll
+    fn wide_code_at(&self, point: LayoutPoint) -> Option<usize> {
+        self.built_model.iter().zip(&self.block_tops).position(|(block, &top)| match *block {
+            BuiltBlock::Text(ref text) => text.scroll_width() > 0.0 && point.y >= top && point.y <= top + text.height,
+            _ => false,
+        })
+    }
+
+    /// Scrolls a wide code block sideways, no further than its longest line
+    fn scroll_code(&mut self, index: usize, dx: f32, api: &RenderApi, document_id: DocumentId) {
+        let max_x = match self.built_model[index] {
+            BuiltBlock::Text(ref text) => text.scroll_width(),
+            _ => 0.0,
+        };
+        let x = (self.code_scroll.get(&index).cloned().unwrap_or(0.0) - dx).clamp(0.0, max_x);
+        self.set_code_scroll(index, x, api, document_id);
+    }
+
+    fn set_code_scroll(&mut self, index: usize, x: f32, api: &RenderApi, document_id: DocumentId) {
+        let x = x.round();
+        self.code_scroll.insert(index, x);
+        api.scroll_node_with_id(document_id, LayoutPoint::new(x, 0.0),
+            self.code_scroll_frame(index), ScrollClamping::NoClamping);
+    }
+
+    /// Scrolls code blocks back to their start, since the scroll frames are reused by the next document
+    fn reset_code_scroll(&mut self, api: &RenderApi, document_id: DocumentId) {
+        let scrolled: Vec<usize> = self.code_scroll.drain().map(|(index, _)| index).collect();
+        for index in scrolled {
+            api.scroll_node_with_id(document_id, LayoutPoint::zero(),
+                self.code_scroll_frame(index), ScrollClamping::NoClamping);
+        }
+    }
+
+    pub fn total_height(&self) -> f32 {
+        self.total_height
+    }
+
+    pub fn diagnostics(&self) -> &[ImageError] {
+        &self.diagnostics
+    }
+
+    /// Finds the link under a point in document coordinates
+    fn link_at(&self, point: LayoutPoint, layout_size: LayoutSize) -> Option<&Link> {
+        let x = self.left_edge(layout_size);
+        self.built_model.iter().zip(&self.block_tops)
+            .filter(|&(block, &top)| point.y >= top && point.y <= top + block.height(self.column_width))
+            .filter_map(|(block, &top)| block.link_at(LayoutPoint::new(x, top), point))
+            .next()
+    }
+
+    /// Loads relative Markdown links in place and hands everything else to the opener
+    fn follow_link(&mut self, dest: &str, api: &RenderApi, document_id: DocumentId) -> bool {
+        if dest.starts_with('#') {
+            // TODO jump to anchors within the document
+            return false;
+        }
+        if dest.contains("://") || dest.starts_with("mailto:") {
+            Self::open_external(dest);
+            return false;
+        }
+
+        let file = dest.split('#').next().unwrap();
+        let target = self.doc_path.parent().unwrap_or_else(|| Path::new("")).join(file);
+        let is_markdown = target.extension().map_or(false, |ext| ext == "md" || ext == "markdown");
+        if !is_markdown {
+            Self::open_external(&target.to_string_lossy());
+            return false;
+        }
+        if let Err(err) = self.load_document(api, &target) {
+            println!("couldn't open {}: {}", target.display(), err);
+            return false;
+        }
+        self.reset_code_scroll(api, document_id);
+        self.scroll_to(0.0, api, document_id);
+        true
+    }
+
+    /// Runs the command in `QUICKDOWN_OPENER`, or the platform default, with the target appended
+    fn open_external(target: &str) {
+        let opener = env::var("QUICKDOWN_OPENER").unwrap_or_else(|_| DEFAULT_OPENER.to_string());
+        let mut parts = opener.split_whitespace();
+        let program = match parts.next() {
+            Some(program) => program,
+            None => return,
+        };
+        if let Err(err) = Command::new(program).args(parts).arg(target).spawn() {
+            println!("couldn't run {:?} to open {}: {}", opener, target, err);
+        }
+    }
+
     pub fn bg_color(&self) -> ColorF {
         self.built_theme.bg_color
     }
@@ -0,0 +1,95 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "usage: fasternet_client <file.md> [--offline] [--render-png <out.png> [--width <pixels>]]";
+
+/// Width of the image `--render-png` makes when no `--width` is given
+const DEFAULT_PNG_WIDTH: u32 = 720;
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub path: String,
+    /// Render to this file instead of opening a window
+    pub render_png: Option<PathBuf>,
+    pub width: u32,
+    /// Only show remote images that are already cached
+    pub offline: bool,
+}
+
+impl Args {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+        let mut path = None;
+        let mut render_png = None;
+        let mut width = None;
+        let mut offline = false;
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--render-png" => {
+                    let out = args.next().ok_or("--render-png needs an output file")?;
+                    render_png = Some(PathBuf::from(out));
+                }
+                "--width" => {
+                    let value = args.next().ok_or("--width needs a number of pixels")?;
+                    match value.parse() {
+                        Ok(pixels) if pixels > 0 => width = Some(pixels),
+                        _ => return Err(format!("invalid width {:?}", value)),
+                    }
+                }
+                "--offline" => offline = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+                _ if path.is_none() => path = Some(arg),
+                _ => return Err(format!("unexpected argument {}", arg)),
+            }
+        }
+
+        if width.is_some() && render_png.is_none() {
+            return Err("--width only applies to --render-png".to_string());
+        }
+        Ok(Args {
+            path: path.ok_or("no file to open")?,
+            render_png,
+            width: width.unwrap_or(DEFAULT_PNG_WIDTH),
+            offline,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn window() {
+        let args = parse(&["Readme.md"]).unwrap();
+        assert_eq!(args.path, "Readme.md");
+        assert_eq!(args.render_png, None);
+        assert!(!args.offline);
+        assert!(parse(&["--offline", "Readme.md"]).unwrap().offline);
+    }
+
+    #[test]
+    fn render_png() {
+        let args = parse(&["--render-png", "out.png", "doc.md", "--width", "400"]).unwrap();
+        assert_eq!(args, Args {
+            path: "doc.md".to_string(),
+            render_png: Some(PathBuf::from("out.png")),
+            width: 400,
+            offline: false,
+        });
+        assert_eq!(parse(&["doc.md", "--render-png", "out.png"]).unwrap().width, DEFAULT_PNG_WIDTH);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["a.md", "b.md"]).is_err());
+        assert!(parse(&["a.md", "--render-png"]).is_err());
+        assert!(parse(&["a.md", "--render-png", "out.png", "--width", "wide"]).is_err());
+        assert!(parse(&["a.md", "--render-png", "out.png", "--width", "0"]).is_err());
+        assert!(parse(&["a.md", "--width", "400"]).is_err());
+        assert!(parse(&["a.md", "--verbose"]).is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! Keeps the textures of a document's images under a memory budget by
+//! dropping the ones farthest off-screen. They're loaded again when they get
+//! close to the viewport.
+
+use std::collections::HashMap;
+use std::env;
+
+/// Used when `QUICKDOWN_TEXTURE_BUDGET` isn't set
+const DEFAULT_MB: usize = 128;
+
+pub struct TextureBudget {
+    limit: usize,
+    /// Bytes each uploaded image takes, by path
+    uploaded: HashMap<String, usize>,
+}
+
+impl TextureBudget {
+    pub fn new(limit: usize) -> TextureBudget {
+        TextureBudget { limit, uploaded: HashMap::new() }
+    }
+
+    /// Takes the limit in megabytes from `QUICKDOWN_TEXTURE_BUDGET`
+    pub fn from_env() -> TextureBudget {
+        let mb = match env::var("QUICKDOWN_TEXTURE_BUDGET") {
+            Ok(value) => value.parse().unwrap_or_else(|_| {
+                println!("warning: ignoring QUICKDOWN_TEXTURE_BUDGET={:?}, it should be a number of megabytes", value);
+                DEFAULT_MB
+            }),
+            Err(_) => DEFAULT_MB,
+        };
+        Self::new(mb.saturating_mul(1024 * 1024))
+    }
+
+    pub fn insert(&mut self, path: &str, bytes: usize) {
+        self.uploaded.insert(path.to_string(), bytes);
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.uploaded.remove(path);
+    }
+
+    /// Picks the images to drop to get back under the limit, farthest from the
+    /// viewport first. Images `keep` or closer are never picked, even if that
+    /// leaves the document over budget.
+    pub fn evictions(&self, distances: &HashMap<String, f32>, keep: f32) -> Vec<String> {
+        let mut used: usize = self.uploaded.values().sum();
+        if used <= self.limit {
+            return Vec::new();
+        }
+
+        let mut candidates: Vec<(&String, f32)> = self.uploaded.keys()
+            .map(|path| (path, distances.get(path).cloned().unwrap_or(f32::INFINITY)))
+            .filter(|&(_, distance)| distance > keep)
+            .collect();
+        candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then_with(|| a.0.cmp(b.0)));
+
+        let mut evicted = Vec::new();
+        for (path, _) in candidates {
+            if used <= self.limit {
+                break;
+            }
+            used -= self.uploaded[path];
+            evicted.push(path.clone());
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn distances(images: &[(&str, f32)]) -> HashMap<String, f32> {
+        images.iter().map(|&(path, distance)| (path.to_string(), distance)).collect()
+    }
+
+    #[test]
+    fn under_budget() {
+        let mut budget = TextureBudget::new(100);
+        budget.insert("a.png", 60);
+        budget.insert("b.png", 40);
+        assert!(budget.evictions(&distances(&[("a.png", 500.0), ("b.png", 900.0)]), 0.0).is_empty());
+    }
+
+    #[test]
+    fn farthest_first() {
+        let mut budget = TextureBudget::new(100);
+        budget.insert("near.png", 50);
+        budget.insert("far.png", 50);
+        budget.insert("farther.png", 50);
+        budget.insert("farthest.png", 50);
+        let distances = distances(&[("near.png", 10.0), ("far.png", 500.0), ("farther.png", 600.0), ("farthest.png", 700.0)]);
+        assert_eq!(budget.evictions(&distances, 0.0), vec!["farthest.png", "farther.png"]);
+
+        budget.remove("farthest.png");
+        assert_eq!(budget.evictions(&distances, 0.0), vec!["farther.png"]);
+    }
+
+    #[test]
+    fn keeps_visible_images_over_budget() {
+        let mut budget = TextureBudget::new(100);
+        budget.insert("visible.png", 150);
+        budget.insert("close.png", 10);
+        budget.insert("gone.png", 10);
+        let distances = distances(&[("visible.png", 0.0), ("close.png", 300.0)]);
+        // images that aren't in the document anymore go first
+        assert_eq!(budget.evictions(&distances, 400.0), vec!["gone.png"]);
+    }
+}
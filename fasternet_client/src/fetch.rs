@@ -0,0 +1,255 @@
+//! Fetches remote images through an on-disk cache. Bodies are stored under
+//! the hash of their contents, and each URL has an entry naming its body and
+//! the ETag to revalidate it with, so unchanged images are never downloaded twice.
+
+use dirs;
+use sha2::{Digest, Sha256};
+use ureq;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+
+/// Responses bigger than this aren't images anyone wants to wait for
+const MAX_BYTES: u64 = 64 * 1024 * 1024;
+const TIMEOUT_SECS: u64 = 30;
+
+pub struct Cache {
+    dir: PathBuf,
+    /// Only serve what's already cached, never touch the network
+    offline: bool,
+    agent: ureq::Agent,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// Running with `--offline` and the URL was never fetched
+    NotCached,
+    /// The server answered with an error status
+    Status(u16),
+    TooLarge,
+    /// The server couldn't be reached or the connection broke
+    Network(String),
+    /// The cache folder couldn't be read or written
+    Io(io::Error),
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FetchError::NotCached => write!(f, "not in the cache and running offline"),
+            FetchError::Status(code) => write!(f, "server responded with status {}", code),
+            FetchError::TooLarge => write!(f, "bigger than {}MB", MAX_BYTES / 1024 / 1024),
+            FetchError::Network(ref err) => write!(f, "{}", err),
+            FetchError::Io(ref err) => write!(f, "cache error: {}", err),
+        }
+    }
+}
+
+impl Error for FetchError {}
+
+impl From<io::Error> for FetchError {
+    fn from(err: io::Error) -> FetchError {
+        FetchError::Io(err)
+    }
+}
+
+/// What the cache knows about a URL
+struct Entry {
+    body: String,
+    etag: Option<String>,
+}
+
+pub fn is_remote(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf, offline: bool) -> Cache {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(TIMEOUT_SECS))
+            .build();
+        Cache { dir, offline, agent }
+    }
+
+    /// The user's cache folder, or one in the temp folder on platforms without one
+    pub fn default_dir() -> PathBuf {
+        dirs::cache_dir().unwrap_or_else(env::temp_dir).join("fasternet")
+    }
+
+    /// Gets the body at `url`, from the cache if the server says it hasn't changed
+    pub fn get(&self, url: &str) -> Result<Vec<u8>, FetchError> {
+        let entry = self.entry(url);
+        if self.offline {
+            return match entry {
+                Some(entry) => Ok(fs::read(self.body_path(&entry.body))?),
+                None => Err(FetchError::NotCached),
+            };
+        }
+
+        let mut request = self.agent.get(url);
+        if let Some(etag) = entry.as_ref().and_then(|entry| entry.etag.as_ref()) {
+            request = request.set("If-None-Match", etag);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(code, _)) => return Err(FetchError::Status(code)),
+            // a stale image is better than none
+            Err(err) => return match entry {
+                Some(entry) => Ok(fs::read(self.body_path(&entry.body))?),
+                None => Err(FetchError::Network(err.to_string())),
+            },
+        };
+
+        if response.status() == 304 {
+            if let Some(entry) = entry {
+                return Ok(fs::read(self.body_path(&entry.body))?);
+            }
+        }
+        let etag = response.header("ETag").map(|etag| etag.to_string());
+        let mut bytes = Vec::new();
+        response.into_reader().take(MAX_BYTES + 1).read_to_end(&mut bytes)
+            .map_err(|err| FetchError::Network(err.to_string()))?;
+        if bytes.len() as u64 > MAX_BYTES {
+            return Err(FetchError::TooLarge);
+        }
+        self.store(url, &bytes, etag)?;
+        Ok(bytes)
+    }
+
+    fn entry(&self, url: &str) -> Option<Entry> {
+        let contents = fs::read_to_string(self.entry_path(url)).ok()?;
+        let mut lines = contents.lines();
+        let body = lines.next()?.to_string();
+        let etag = lines.next().filter(|etag| !etag.is_empty()).map(|etag| etag.to_string());
+        // the body could have been cleaned up on its own
+        if !self.body_path(&body).exists() {
+            return None;
+        }
+        Some(Entry { body, etag })
+    }
+
+    fn store(&self, url: &str, bytes: &[u8], etag: Option<String>) -> io::Result<()> {
+        let body = hash(bytes);
+        let body_path = self.body_path(&body);
+        if !body_path.exists() {
+            write_atomic(&body_path, bytes)?;
+        }
+        let entry = format!("{}\n{}\n", body, etag.unwrap_or_default());
+        write_atomic(&self.entry_path(url), entry.as_bytes())
+    }
+
+    fn entry_path(&self, url: &str) -> PathBuf {
+        self.dir.join("urls").join(hash(url.as_bytes()))
+    }
+
+    fn body_path(&self, body: &str) -> PathBuf {
+        self.dir.join("bodies").join(body)
+    }
+}
+
+fn hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes to a temporary file first so other instances never read half a file
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension(format!("{}.tmp", process::id()));
+    fs::write(&temp, bytes)?;
+    fs::rename(&temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers one request per response on a loopback port, then returns the requests' headers
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.png", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            responses.into_iter().map(|response| {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while reader.read_line(&mut head).unwrap() > 2 {}
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+                head
+            }).collect()
+        });
+        (url, handle)
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("fasternet-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn revalidates_with_etag() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+            "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n",
+        ]);
+        let cache = Cache::new(cache_dir("etag"), false);
+        assert_eq!(cache.get(&url).unwrap(), b"hello");
+        assert_eq!(cache.get(&url).unwrap(), b"hello");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"v1\""), "{}", requests[1]);
+    }
+
+    #[test]
+    fn replaces_changed_body() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 3\r\nConnection: close\r\n\r\nold",
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nnew",
+        ]);
+        let dir = cache_dir("changed");
+        let cache = Cache::new(dir.clone(), false);
+        assert_eq!(cache.get(&url).unwrap(), b"old");
+        assert_eq!(cache.get(&url).unwrap(), b"new");
+        server.join().unwrap();
+
+        let offline = Cache::new(dir, true);
+        assert_eq!(offline.get(&url).unwrap(), b"new");
+        assert!(offline.entry(&url).unwrap().etag.is_none());
+    }
+
+    #[test]
+    fn offline_only_uses_cache() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nhi",
+        ]);
+        let dir = cache_dir("offline");
+        let offline = Cache::new(dir.clone(), true);
+        assert!(matches!(offline.get(&url), Err(FetchError::NotCached)));
+
+        Cache::new(dir, false).get(&url).unwrap();
+        server.join().unwrap();
+        // the server is gone now
+        assert_eq!(offline.get(&url).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn error_status() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        let cache = Cache::new(cache_dir("status"), false);
+        assert!(matches!(cache.get(&url), Err(FetchError::Status(404))));
+        server.join().unwrap();
+    }
+}
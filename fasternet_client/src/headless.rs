@@ -0,0 +1,173 @@
+//! Renders a whole document to a PNG with an offscreen GL context instead of
+//! a window. On Linux glutin makes these with OSMesa, so it works on machines
+//! with no display or GPU.
+
+use gleam::gl;
+use glutin::{self, GlContext};
+use image;
+use webrender;
+use webrender::api::*;
+use std::path::Path;
+use std::sync::mpsc;
+
+use app::App;
+use budget::TextureBudget;
+use fetch::Cache;
+
+/// Documents are rendered a screenful at a time and stitched together,
+/// since GL implementations limit how big a framebuffer can be
+const TILE_HEIGHT: u32 = 1024;
+
+/// Lets the main thread block until a frame is ready to render
+struct Notifier {
+    frame_ready: mpsc::Sender<()>,
+}
+
+impl RenderNotifier for Notifier {
+    fn new_document_ready(&self, _id: DocumentId, scrolled: bool, _composite_needed: bool) {
+        // scrolling reports in too, only frames from `generate_frame` are waited for
+        if !scrolled {
+            let _ = self.frame_ready.send(());
+        }
+    }
+
+    fn wake_up(&self) {}
+
+    fn clone(&self) -> Box<RenderNotifier + 'static> {
+        Box::new(Notifier { frame_ready: self.frame_ready.clone() })
+    }
+}
+
+pub fn render_png(doc_path: &str, out: &Path, width: u32, cache: Cache) -> Result<(), String> {
+    let context = glutin::HeadlessRendererBuilder::new(width, TILE_HEIGHT)
+        .with_gl(glutin::GlRequest::GlThenGles {
+            opengl_version: (3, 2),
+            opengles_version: (3, 0)
+        })
+        .build()
+        .map_err(|err| format!("couldn't create an offscreen GL context: {}", err))?;
+    unsafe { context.make_current() }.map_err(|err| format!("couldn't use the GL context: {:?}", err))?;
+
+    let gl = match gl::GlType::default() {
+        gl::GlType::Gl => unsafe { gl::GlFns::load_with(|symbol| context.get_proc_address(symbol) as *const _) },
+        gl::GlType::Gles => unsafe { gl::GlesFns::load_with(|symbol| context.get_proc_address(symbol) as *const _) },
+    };
+
+    let opts = webrender::RendererOptions {
+        precache_shaders: false,
+        enable_subpixel_aa: false, // the PNG could be shown on any kind of screen
+        enable_aa: true,
+        device_pixel_ratio: 1.0,
+        .. webrender::RendererOptions::default()
+    };
+    let (frame_ready, frames) = mpsc::channel();
+    let notifier = Box::new(Notifier { frame_ready });
+    let image_notifier = notifier.clone();
+    let (mut renderer, sender) = webrender::Renderer::new(gl.clone(), notifier, opts)
+        .map_err(|err| format!("couldn't start Webrender: {:?}", err))?;
+    let api = sender.create_api();
+    let tile_size = DeviceUintSize::new(width, TILE_HEIGHT);
+    let document_id = api.add_document(tile_size, 0);
+    let pipeline_id = PipelineId(0, 0);
+
+    let layout_size = LayoutSize::new(width as f32, TILE_HEIGHT as f32);
+    let mut app = App::new(&api, pipeline_id, doc_path, layout_size, 1.0, cache, image_notifier)
+        .map_err(|err| format!("couldn't open {}: {}", doc_path, err))?;
+    // every tile is rendered from the same upload, so nothing can be evicted
+    app.set_texture_budget(TextureBudget::new(usize::MAX));
+    // there's only one frame, so it has to have every image and highlighted block in it
+    app.wait_for_highlights();
+    app.wait_for_images(&api);
+
+    let mut builder = DisplayListBuilder::new(pipeline_id, layout_size);
+    let mut resources = ResourceUpdates::new();
+    app.render(&api, &mut builder, &mut resources, layout_size, pipeline_id, document_id);
+    api.set_display_list(
+        document_id,
+        Epoch(0),
+        Some(app.bg_color()),
+        layout_size,
+        builder.finalize(),
+        true,
+        resources
+    );
+    api.set_root_pipeline(document_id, pipeline_id);
+
+    let height = png_height(app.total_height());
+    let row_len = (width * 4) as usize;
+    let mut pixels = vec![0u8; row_len * height as usize];
+    let mut top = 0;
+    while top < height {
+        app.scroll_to(top as f32, &api, document_id);
+        // anything that came in before this tile's frame was asked for is for an older one
+        while frames.try_recv().is_ok() {}
+        api.generate_frame(document_id, None);
+        frames.recv().map_err(|_| "Webrender stopped before rendering".to_string())?;
+        renderer.update();
+        renderer.render(tile_size).map_err(|errs| format!("couldn't render: {:?}", errs))?;
+
+        let tile = gl.read_pixels(0, 0, width as i32, TILE_HEIGHT as i32, gl::RGBA, gl::UNSIGNED_BYTE);
+        // GL rows go from the bottom up
+        for row in 0..TILE_HEIGHT.min(height - top) {
+            let src = (TILE_HEIGHT - 1 - row) as usize * row_len;
+            let dst = (top + row) as usize * row_len;
+            pixels[dst..(dst + row_len)].copy_from_slice(&tile[src..(src + row_len)]);
+        }
+        top += TILE_HEIGHT;
+    }
+    renderer.deinit();
+
+    if !app.diagnostics().is_empty() {
+        eprintln!("{} image(s) couldn't be loaded and were replaced by placeholders", app.diagnostics().len());
+    }
+    save_png(out, &pixels, width, height)
+}
+
+/// PNGs can't be empty, so a document with nothing in it is a single blank row
+fn png_height(document_height: f32) -> u32 {
+    (document_height.ceil() as u32).max(1)
+}
+
+fn save_png(out: &Path, pixels: &[u8], width: u32, height: u32) -> Result<(), String> {
+    image::save_buffer(out, pixels, width, height, image::ColorType::Rgba8)
+        .map_err(|err| format!("couldn't write {}: {}", out.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    #[ignore = "needs an OpenGL or OSMesa context"]
+    fn every_tile_is_its_own_frame() {
+        let dir = env::temp_dir().join(format!("fasternet-headless-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let doc = dir.join("long.md");
+        let text: String = (0..300).map(|i| format!("Paragraph number {}\n\n", i)).collect();
+        fs::write(&doc, text).unwrap();
+        let out = dir.join("long.png");
+        render_png(doc.to_str().unwrap(), &out, 400, Cache::new(dir.join("cache"), true)).unwrap();
+
+        let image = image::open(&out).unwrap().to_rgba8();
+        assert_eq!(image.width(), 400);
+        assert!(image.height() > TILE_HEIGHT * 2, "{}", image.height());
+        // a tile stitched from a stale frame repeats the one above it
+        let tiles: Vec<&[u8]> = image.as_raw().chunks((400 * TILE_HEIGHT * 4) as usize).collect();
+        for (i, pair) in tiles.windows(2).enumerate() {
+            assert!(pair[0] != pair[1], "tile {} is the same as the one above it", i + 1);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn empty_document() {
+        let out = env::temp_dir().join(format!("fasternet-empty-{}.png", process::id()));
+        let height = png_height(0.0);
+        save_png(&out, &vec![0; 16 * 4 * height as usize], 16, height).unwrap();
+        assert_eq!(image::image_dimensions(&out).unwrap(), (16, 1));
+        let _ = fs::remove_file(&out);
+
+        assert_eq!(png_height(20.5), 21);
+    }
+}
@@ -0,0 +1,201 @@
+//! Highlights fenced code blocks with syntect's grammars. Only a handful of
+//! token kinds are picked out and the theme decides their colors. Grammars
+//! are slow to load and big blocks slow to parse, so it's done in the
+//! background and each block is handed back when it's done.
+
+use fasternet_common::{Chunk, TextKind};
+use jobs::Jobs;
+use std::sync::{Arc, OnceLock};
+use syntect::easy::ScopeRangeIterator;
+use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+/// Which kind a token gets when its scope starts with one of these. A
+/// token's innermost scope with a match wins, so the quotes of a string,
+/// scoped `punctuation.definition.string` inside `string`, are part of it.
+const SCOPE_KINDS: &[(&str, TextKind)] = &[
+    ("comment", TextKind::CodeComment),
+    ("string", TextKind::CodeString),
+    ("constant", TextKind::CodeConstant),
+    ("entity.name.function", TextKind::CodeFunction),
+    ("support.function", TextKind::CodeFunction),
+    ("variable.function", TextKind::CodeFunction),
+    ("entity.name", TextKind::CodeType),
+    ("support.type", TextKind::CodeType),
+    ("support.class", TextKind::CodeType),
+    // operators are everywhere, coloring them is just noise
+    ("keyword.operator", TextKind::CodeBlock),
+    ("keyword", TextKind::CodeKeyword),
+    ("storage", TextKind::CodeKeyword),
+];
+
+struct Grammars {
+    syntaxes: SyntaxSet,
+    scope_kinds: Vec<(Scope, TextKind)>,
+}
+
+fn grammars() -> &'static Grammars {
+    static GRAMMARS: OnceLock<Grammars> = OnceLock::new();
+    GRAMMARS.get_or_init(|| Grammars {
+        syntaxes: SyntaxSet::load_defaults_newlines(),
+        scope_kinds: SCOPE_KINDS.iter().map(|&(scope, kind)| (Scope::new(scope).unwrap(), kind)).collect(),
+    })
+}
+
+/// Splits `code` into chunks by token kind, or returns `None` if there's no
+/// grammar for `language`. It's matched against names and file extensions,
+/// so both `rust` and `rs` work.
+pub fn highlight(language: &str, code: &str) -> Option<Vec<Chunk>> {
+    let grammars = grammars();
+    let syntax = grammars.syntaxes.find_syntax_by_token(language)?;
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut chunks = Vec::new();
+    // the current run of one kind, as a start offset into `code`
+    let (mut run_start, mut run_kind) = (0, TextKind::CodeBlock);
+    let mut line_start = 0;
+    for line in LinesWithEndings::from(code) {
+        let ops = state.parse_line(line, &grammars.syntaxes).ok()?;
+        for (range, op) in ScopeRangeIterator::new(&ops, line) {
+            stack.apply(op).ok()?;
+            if range.is_empty() {
+                continue;
+            }
+            let kind = token_kind(&grammars.scope_kinds, &stack);
+            if kind != run_kind {
+                let start = line_start + range.start;
+                if start > run_start {
+                    Chunk::push_run(&mut chunks, &code[run_start..start], run_kind, None);
+                }
+                run_start = start;
+                run_kind = kind;
+            }
+        }
+        line_start += line.len();
+    }
+    if code.len() > run_start {
+        Chunk::push_run(&mut chunks, &code[run_start..], run_kind, None);
+    }
+    Some(chunks)
+}
+
+fn token_kind(scope_kinds: &[(Scope, TextKind)], stack: &ScopeStack) -> TextKind {
+    stack.as_slice().iter().rev()
+        .filter_map(|&scope| {
+            scope_kinds.iter().find(|&&(prefix, _)| prefix.is_prefix_of(scope)).map(|&(_, kind)| kind)
+        })
+        .next()
+        .unwrap_or(TextKind::CodeBlock)
+}
+
+pub struct HighlightedBlock {
+    /// Index of the block in the document
+    pub index: usize,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Highlights a document's code blocks in the background, like `Loader` does for images
+pub struct Highlighter {
+    jobs: Jobs<HighlightedBlock>,
+}
+
+impl Highlighter {
+    /// Calls `wake` after each block is done
+    pub fn new(wake: Arc<Fn() + Send + Sync>) -> Highlighter {
+        Highlighter { jobs: Jobs::new(wake) }
+    }
+
+    /// Starts highlighting blocks, given as their index, language and code
+    pub fn highlight(&mut self, blocks: Vec<(usize, String, String)>) {
+        self.jobs.run(blocks, |(index, language, code)| {
+            // blocks in unknown languages are handed back unchanged so they're not waited on
+            let chunks = highlight(&language, &code).unwrap_or_else(|| {
+                let mut chunks = Vec::new();
+                Chunk::push_run(&mut chunks, &code, TextKind::CodeBlock, None);
+                chunks
+            });
+            HighlightedBlock { index, chunks }
+        });
+    }
+
+    /// Takes the blocks that finished since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<HighlightedBlock> {
+        self.jobs.poll()
+    }
+
+    /// Blocks until every requested block is done
+    pub fn wait(&mut self) -> Vec<HighlightedBlock> {
+        self.jobs.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The kinds of the chunks covering `token`, which must be in `code` once
+    fn kinds_of(code: &str, chunks: &[Chunk], token: &str) -> Vec<TextKind> {
+        assert_eq!(code.matches(token).count(), 1, "{:?} should be in the code once", token);
+        let start = code.find(token).unwrap();
+        let end = start + token.len();
+        let mut kinds = Vec::new();
+        let mut offset = 0;
+        for chunk in chunks {
+            let chunk_end = offset + chunk.len as usize;
+            if chunk_end > start && offset < end && !kinds.contains(&chunk.kind) {
+                kinds.push(chunk.kind);
+            }
+            offset = chunk_end;
+        }
+        kinds
+    }
+
+    #[test]
+    fn rust_tokens() {
+        let code = "// entry point\nfn main() {\n    let answer = 42;\n    println!(\"{} hi\", answer);\n}\n";
+        let chunks = highlight("rust", code).unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len as usize).sum::<usize>(), code.len());
+        assert_eq!(kinds_of(code, &chunks, "// entry point"), vec![TextKind::CodeComment]);
+        assert_eq!(kinds_of(code, &chunks, "fn"), vec![TextKind::CodeKeyword]);
+        assert_eq!(kinds_of(code, &chunks, "main"), vec![TextKind::CodeFunction]);
+        assert_eq!(kinds_of(code, &chunks, "42"), vec![TextKind::CodeConstant]);
+        assert_eq!(kinds_of(code, &chunks, " hi\""), vec![TextKind::CodeString]);
+        // format string placeholders stand out from the rest of the string
+        assert_eq!(kinds_of(code, &chunks, "{} "), vec![TextKind::CodeConstant, TextKind::CodeString]);
+        assert_eq!(kinds_of(code, &chunks, " = "), vec![TextKind::CodeBlock]);
+        // neighbouring tokens of a kind are one chunk
+        assert!(chunks.windows(2).all(|pair| pair[0].kind != pair[1].kind));
+    }
+
+    #[test]
+    fn extensions_and_unknown_languages() {
+        let code = "def f():\n    return None\n";
+        let chunks = highlight("py", code).unwrap();
+        assert_eq!(kinds_of(code, &chunks, "def"), vec![TextKind::CodeKeyword]);
+        assert_eq!(kinds_of(code, &chunks, "None"), vec![TextKind::CodeConstant]);
+        assert!(highlight("not-a-language", code).is_none());
+    }
+
+    #[test]
+    fn long_tokens_are_split() {
+        let code = format!("\"{}\"\n", "a".repeat(70_000));
+        let chunks = highlight("js", &code).unwrap();
+        assert_eq!(chunks.iter().map(|chunk| chunk.len as usize).sum::<usize>(), code.len());
+        assert!(chunks.iter().filter(|chunk| chunk.kind == TextKind::CodeString).count() >= 2);
+    }
+
+    #[test]
+    fn highlights_in_background() {
+        let mut highlighter = Highlighter::new(Arc::new(|| ()));
+        highlighter.highlight(vec![
+            (3, "rust".to_string(), "let x = 1;\n".to_string()),
+            (7, "nope".to_string(), "let x = 1;\n".to_string()),
+        ]);
+        let mut done = highlighter.wait();
+        done.sort_by_key(|block| block.index);
+        assert_eq!(done.iter().map(|block| block.index).collect::<Vec<_>>(), vec![3, 7]);
+        assert!(done[0].chunks.len() > 1);
+        assert_eq!(done[1].chunks, vec![Chunk { len: 11, kind: TextKind::CodeBlock, link: None }]);
+        assert!(highlighter.poll().is_empty());
+    }
+}
@@ -0,0 +1,384 @@
+//! Reads and decodes images into pixels Webrender can take. Nothing here
+//! panics on a bad file, callers get an `ImageError` to show instead.
+
+use fetch::{self, Cache, FetchError};
+use image::{self, AnimationDecoder, DynamicImage, GenericImageView, ImageFormat};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::imageops::FilterType;
+use resvg::usvg;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use svg;
+
+/// Animations stop being decoded past this many bytes of frames, and play
+/// the ones that fit
+const MAX_ANIMATION_BYTES: usize = 256 * 1024 * 1024;
+/// Browsers play frames with delays this short at `DEFAULT_DELAY_MS`, and
+/// files rely on that
+const MIN_DELAY_MS: u32 = 10;
+const DEFAULT_DELAY_MS: u32 = 100;
+/// How much of an SVG is read to find its size before it's loaded
+const SVG_HEAD_LEN: u64 = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+    Rgb8,
+    /// Premultiplied alpha
+    Bgra8,
+}
+
+pub struct DecodedImage {
+    /// Size of the pixels, which can be smaller than the file's if it was downscaled
+    pub width: u32,
+    pub height: u32,
+    /// Size of the image in the file, which is what it's laid out with
+    pub natural_width: u32,
+    pub natural_height: u32,
+    pub format: PixelFormat,
+    pub opaque: bool,
+    pub bytes: Vec<u8>,
+    /// Every frame of an animated image including the first, empty for still images
+    pub frames: Vec<Frame>,
+}
+
+/// A frame of an animation, in the same format and size as the image it's part of
+pub struct Frame {
+    pub bytes: Arc<Vec<u8>>,
+    /// How long it's shown before the next one
+    pub delay: Duration,
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// The file couldn't be read
+    Io(PathBuf, io::Error),
+    /// The URL couldn't be fetched
+    Fetch(String, FetchError),
+    /// The file was read but isn't an image we can decode
+    Decode(PathBuf, image::ImageError),
+    /// The file looked like an SVG but couldn't be drawn
+    Svg(PathBuf, usvg::Error),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImageError::Io(ref path, ref err) => write!(f, "couldn't read image {}: {}", path.display(), err),
+            ImageError::Fetch(ref url, ref err) => write!(f, "couldn't fetch image {}: {}", url, err),
+            ImageError::Decode(ref path, ref err) => write!(f, "couldn't decode image {}: {}", path.display(), err),
+            ImageError::Svg(ref path, ref err) => write!(f, "couldn't draw SVG {}: {}", path.display(), err),
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+/// Loads an image referenced from a document in `dir`, which can also be an
+/// http(s) URL, shrinking it to `max_width` pixels wide if it's bigger. SVGs
+/// are drawn exactly `max_width` wide instead.
+pub fn load(dir: &Path, path: &str, cache: &Cache, max_width: u32) -> Result<DecodedImage, ImageError> {
+    if fetch::is_remote(path) {
+        let bytes = cache.get(path).map_err(|err| ImageError::Fetch(path.to_string(), err))?;
+        return decode_file(&bytes, path, PathBuf::from(path), None, max_width);
+    }
+
+    let full_path = dir.join(path);
+    let mut bytes = Vec::new();
+    if let Err(err) = File::open(&full_path).and_then(|mut file| file.read_to_end(&mut bytes)) {
+        return Err(ImageError::Io(full_path, err));
+    }
+    let svg_dir = full_path.parent().map(Path::to_path_buf);
+    decode_file(&bytes, path, full_path, svg_dir.as_deref(), max_width)
+}
+
+/// Decodes the contents of `path`, rasterizing it if it's an SVG
+fn decode_file(bytes: &[u8], path: &str, full_path: PathBuf, svg_dir: Option<&Path>, max_width: u32)
+               -> Result<DecodedImage, ImageError> {
+    if svg::is_svg(path, bytes) {
+        svg::rasterize(bytes, svg_dir, max_width).map_err(|err| ImageError::Svg(full_path, err))
+    } else {
+        decode(bytes, max_width).map_err(|err| ImageError::Decode(full_path, err))
+    }
+}
+
+/// Reads just enough of a local image to know its size. Remote images
+/// aren't known until they're fetched.
+pub fn dimensions(dir: &Path, path: &str) -> Option<(u32, u32)> {
+    if fetch::is_remote(path) {
+        return None;
+    }
+    if svg::has_svg_extension(path) {
+        // the root tag comes before any of the drawing, the rest is left for the loader
+        let mut head = Vec::new();
+        File::open(dir.join(path)).and_then(|file| file.take(SVG_HEAD_LEN).read_to_end(&mut head)).ok()?;
+        return svg::size(&head);
+    }
+    image::io::Reader::open(dir.join(path)).ok()?
+        .with_guessed_format().ok()?
+        .into_dimensions().ok()
+}
+
+pub fn decode(bytes: &[u8], max_width: u32) -> Result<DecodedImage, image::ImageError> {
+    if let Some(animation) = decode_animation(bytes, max_width)? {
+        return Ok(animation);
+    }
+    let mut image = image::load_from_memory(bytes)?;
+    let (natural_width, natural_height) = image.dimensions();
+    // there's no point keeping pixels that will never be seen on screen
+    if natural_width > max_width {
+        image = image.resize(max_width, natural_height, FilterType::Triangle);
+    }
+    Ok(match image {
+        DynamicImage::ImageRgb8(rgb) => {
+            let (width, height) = rgb.dimensions();
+            DecodedImage {
+                width, height, natural_width, natural_height,
+                format: PixelFormat::Rgb8, opaque: true, bytes: rgb.into_raw(), frames: Vec::new(),
+            }
+        }
+        // Webrender only takes a few formats, so grey, 16-bit and float images become RGBA
+        other => {
+            let rgba = other.into_rgba8();
+            let (width, height) = rgba.dimensions();
+            let mut bytes = rgba.into_raw();
+            let opaque = bytes.chunks(4).all(|pixel| pixel[3] == 255);
+            premultiply(&mut bytes);
+            DecodedImage {
+                width, height, natural_width, natural_height,
+                format: PixelFormat::Bgra8, opaque, bytes, frames: Vec::new(),
+            }
+        }
+    })
+}
+
+/// Decodes every frame of an animated GIF or APNG. Anything else, including
+/// GIFs with a single frame, is left to `decode`.
+fn decode_animation(bytes: &[u8], max_width: u32) -> Result<Option<DecodedImage>, image::ImageError> {
+    let frames = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(Cursor::new(bytes))?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    let mut decoded = Vec::new();
+    let (mut natural_width, mut natural_height) = (0, 0);
+    let (mut width, mut height) = (0, 0);
+    let mut total = 0;
+    for frame in frames {
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let delay_ms = if denom == 0 || numer / denom <= MIN_DELAY_MS { DEFAULT_DELAY_MS } else { numer / denom };
+        let mut image = DynamicImage::ImageRgba8(frame.into_buffer());
+        // frames come out composited onto the full canvas, so they're all the same size
+        let size = image.dimensions();
+        natural_width = size.0;
+        natural_height = size.1;
+        if natural_width > max_width {
+            image = image.resize(max_width, natural_height, FilterType::Triangle);
+        }
+        let rgba = image.into_rgba8();
+        width = rgba.width();
+        height = rgba.height();
+        let mut bytes = rgba.into_raw();
+        premultiply(&mut bytes);
+        total += bytes.len();
+        decoded.push(Frame { bytes: Arc::new(bytes), delay: Duration::from_millis(delay_ms as u64) });
+        if total >= MAX_ANIMATION_BYTES {
+            break;
+        }
+    }
+    if decoded.len() < 2 {
+        return Ok(None);
+    }
+
+    let opaque = decoded.iter().all(|frame| frame.bytes.chunks(4).all(|pixel| pixel[3] == 255));
+    Ok(Some(DecodedImage {
+        width, height, natural_width, natural_height,
+        format: PixelFormat::Bgra8, opaque,
+        bytes: decoded[0].bytes.to_vec(),
+        frames: decoded,
+    }))
+}
+
+/// Converts RGBA to premultiplied BGRA in place
+pub fn premultiply(data: &mut [u8]) {
+    for pixel in data.chunks_mut(4) {
+        let a = pixel[3] as u32;
+        let b = pixel[2] as u32;
+        let g = pixel[1] as u32;
+        let r = pixel[0] as u32;
+
+        pixel[3] = a as u8;
+        pixel[2] = ((r * a + 128) / 255) as u8;
+        pixel[1] = ((g * a + 128) / 255) as u8;
+        pixel[0] = ((b * a + 128) / 255) as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn testdata() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata")
+    }
+
+    fn load(path: &str) -> Result<DecodedImage, ImageError> {
+        let cache = Cache::new(testdata().join("no-cache"), true);
+        super::load(&testdata(), path, &cache, u32::MAX)
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = io::Cursor::new(Vec::new());
+        DynamicImage::new_rgb8(width, height).write_to(&mut bytes, image::ImageOutputFormat::Png).unwrap();
+        bytes.into_inner()
+    }
+
+    /// A GIF whose frames are filled with each of `colors`, shown for 30ms each
+    fn gif(width: u32, height: u32, colors: &[[u8; 4]]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut bytes);
+            let frames = colors.iter().map(|&color| {
+                let buffer = image::RgbaImage::from_pixel(width, height, image::Rgba(color));
+                image::Frame::from_parts(buffer, 0, 0, image::Delay::from_numer_denom_ms(30, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn missing_file() {
+        match load("nope.png") {
+            Err(ImageError::Io(path, err)) => {
+                assert_eq!(err.kind(), io::ErrorKind::NotFound);
+                assert!(path.ends_with("nope.png"));
+            }
+            Err(err) => panic!("wrong error {}", err),
+            Ok(_) => panic!("loaded a missing file"),
+        }
+    }
+
+    #[test]
+    fn remote_uncached_offline() {
+        let err = load("https://example.com/logo.png").err().expect("fetched while offline");
+        assert!(matches!(err, ImageError::Fetch(ref url, FetchError::NotCached) if url.ends_with("logo.png")), "{}", err);
+    }
+
+    #[test]
+    fn header_dimensions() {
+        assert_eq!(dimensions(&testdata(), "rgba16.png"), Some((1, 2)));
+        assert_eq!(dimensions(&testdata(), "nope.png"), None);
+        assert_eq!(dimensions(&testdata(), "https://example.com/logo.png"), None);
+        assert_eq!(dimensions(&testdata(), "diagram.svg"), Some((120, 60)));
+    }
+
+    #[test]
+    fn svg_file() {
+        let image = load("diagram.svg").unwrap();
+        assert_eq!((image.width, image.height), (120, 60));
+        let cache = Cache::new(testdata().join("no-cache"), true);
+        let image = super::load(&testdata(), "diagram.svg", &cache, 240).unwrap();
+        assert_eq!((image.width, image.height), (240, 120));
+        assert_eq!((image.natural_width, image.natural_height), (120, 60));
+
+        let err = load("corrupt.svg").err().expect("drew a corrupt SVG");
+        assert!(matches!(err, ImageError::Svg(ref path, _) if path.ends_with("corrupt.svg")), "{}", err);
+    }
+
+    #[test]
+    fn downscales_to_max_width() {
+        let image = decode(&png(40, 20), 10).unwrap();
+        assert_eq!((image.width, image.height), (10, 5));
+        assert_eq!((image.natural_width, image.natural_height), (40, 20));
+        assert_eq!(image.bytes.len(), 10 * 5 * 3);
+
+        let image = decode(&png(40, 20), 100).unwrap();
+        assert_eq!((image.width, image.height), (40, 20));
+    }
+
+    #[test]
+    fn animated_gif() {
+        let image = decode(&gif(40, 20, &[[255, 0, 0, 255], [0, 0, 255, 255], [0, 255, 0, 255]]), 10).unwrap();
+        assert_eq!((image.width, image.height, image.natural_width), (10, 5, 40));
+        assert!(image.opaque);
+        let firsts: Vec<&[u8]> = image.frames.iter().map(|frame| &frame.bytes[..4]).collect();
+        assert_eq!(firsts, vec![&[0, 0, 255, 255], &[255, 0, 0, 255], &[0, 255, 0, 255]]);
+        assert!(image.frames.iter().all(|frame| frame.delay == Duration::from_millis(30)));
+        assert!(image.frames.iter().all(|frame| frame.bytes.len() == 10 * 5 * 4));
+        assert_eq!(image.bytes, *image.frames[0].bytes);
+
+        // a single frame GIF is just an image
+        let image = decode(&gif(2, 2, &[[255, 0, 0, 255]]), 10).unwrap();
+        assert!(image.frames.is_empty());
+    }
+
+    #[test]
+    fn apng() {
+        let image = load("animated.png").unwrap();
+        assert_eq!((image.width, image.height, image.format), (2, 1, PixelFormat::Bgra8));
+        assert!(!image.opaque);
+        assert_eq!(image.frames.len(), 2);
+        assert_eq!(*image.frames[1].bytes, vec![255, 0, 0, 255, 128, 128, 128, 128]);
+        let delays: Vec<Duration> = image.frames.iter().map(|frame| frame.delay).collect();
+        // 10ms is too short to be meant literally
+        assert_eq!(delays, vec![Duration::from_millis(50), Duration::from_millis(100)]);
+    }
+
+    #[test]
+    fn corrupt_file() {
+        let err = load("corrupt.png").err().expect("decoded a corrupt file");
+        assert!(matches!(err, ImageError::Decode(ref path, _) if path.ends_with("corrupt.png")), "{}", err);
+    }
+
+    #[test]
+    fn rgb() {
+        let image = load("rgb.png").unwrap();
+        assert_eq!(image.format, PixelFormat::Rgb8);
+        assert!(image.opaque);
+        assert_eq!(image.bytes, vec![10, 20, 30]);
+    }
+
+    #[test]
+    fn sixteen_bit() {
+        let image = load("gray16.png").unwrap();
+        assert_eq!((image.width, image.height, image.format), (2, 1, PixelFormat::Bgra8));
+        assert!(image.opaque);
+        assert_eq!(image.bytes, vec![0, 0, 0, 255, 255, 255, 255, 255]);
+
+        let image = load("rgba16.png").unwrap();
+        assert_eq!((image.width, image.height), (1, 2));
+        assert!(!image.opaque);
+        assert_eq!(image.bytes, vec![0, 255, 0, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn luma_alpha() {
+        let image = load("luma_alpha.png").unwrap();
+        assert_eq!(image.format, PixelFormat::Bgra8);
+        assert!(!image.opaque);
+        assert_eq!(image.bytes, vec![255, 255, 255, 255, 128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn palette() {
+        let image = load("palette.png").unwrap();
+        assert_eq!((image.width, image.height, image.format), (2, 1, PixelFormat::Bgra8));
+        assert!(!image.opaque);
+        // red, then half transparent blue premultiplied and swizzled to BGRA
+        assert_eq!(image.bytes, vec![0, 0, 255, 255, 128, 0, 0, 128]);
+    }
+}
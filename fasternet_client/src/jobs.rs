@@ -0,0 +1,83 @@
+//! Runs work for the window in the background, the decoding of images and
+//! highlighting of code blocks, and hands each result back as soon as it's
+//! done so the window can show it without waiting on the rest.
+
+use rayon::prelude::*;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+pub struct Jobs<T> {
+    wake: Arc<Fn() + Send + Sync>,
+    sender: Sender<T>,
+    results: Receiver<T>,
+    /// Jobs started but not handed back yet
+    pending: usize,
+}
+
+impl<T: Send + 'static> Jobs<T> {
+    /// Calls `wake` after each job finishes
+    pub fn new(wake: Arc<Fn() + Send + Sync>) -> Jobs<T> {
+        let (sender, results) = mpsc::channel();
+        Jobs { wake, sender, results, pending: 0 }
+    }
+
+    /// Starts running `work` on each input, in parallel
+    pub fn run<I, F>(&mut self, inputs: Vec<I>, work: F)
+        where I: Send + 'static, F: Fn(I) -> T + Send + Sync + 'static
+    {
+        self.pending += inputs.len();
+        let (wake, sender) = (self.wake.clone(), self.sender.clone());
+        thread::spawn(move || {
+            inputs.into_par_iter().for_each_with(sender, |sender, input| {
+                // nobody is listening anymore if another document was opened
+                if sender.send(work(input)).is_ok() {
+                    wake();
+                }
+            });
+        });
+    }
+
+    /// Takes the results that came in since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<T> {
+        let done: Vec<_> = self.results.try_iter().collect();
+        self.pending -= done.len();
+        done
+    }
+
+    /// Blocks until every job that was started is done
+    pub fn wait(&mut self) -> Vec<T> {
+        // we hold a sender too, so this can't fail
+        let done = (0..self.pending).map(|_| self.results.recv().unwrap()).collect();
+        self.pending = 0;
+        done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn hands_back_everything_and_wakes() {
+        let (woke, wakes) = mpsc::channel();
+        let woke = Mutex::new(woke);
+        let mut jobs = Jobs::new(Arc::new(move || woke.lock().unwrap().send(()).unwrap()));
+        jobs.run(vec![1, 2, 3], |n| n * 10);
+        // a result that was polled isn't waited on again
+        wakes.recv().unwrap();
+        let mut done = jobs.poll();
+        assert!(!done.is_empty());
+        jobs.run(vec![4], |n| n * 10);
+        done.extend(jobs.wait());
+        done.sort();
+        assert_eq!(done, vec![10, 20, 30, 40]);
+        assert!(jobs.poll().is_empty());
+        // each wake comes after its result is sent, so they may still be on the way
+        for _ in 0..3 {
+            wakes.recv().unwrap();
+        }
+        assert!(wakes.try_recv().is_err());
+    }
+}
@@ -0,0 +1,382 @@
+//! What the keyboard shortcuts do. Keys are looked up in a table of chords so
+//! every shortcut is listed in one place, rather than each being matched
+//! where it's handled. The defaults can be changed in a `keys.toml` file in
+//! the config directory, mapping chords to the names of actions:
+//!
+//! ```toml
+//! escape = "none"
+//! "ctrl+q" = "quit"
+//! "shift+space" = "page-up"
+//! ```
+
+use glutin::{ModifiersState, VirtualKeyCode};
+use dirs;
+use toml;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    ToggleProfiler,
+    PauseAnimations,
+    Copy,
+    CopyMarkdown,
+    Find,
+    LineUp,
+    LineDown,
+    PageUp,
+    PageDown,
+    Top,
+    Bottom,
+    PreviousHeading,
+    NextHeading,
+    Reload,
+    ZoomIn,
+    ZoomOut,
+    ResetZoom,
+}
+
+/// Every action, along with its name in the config file
+const ACTION_NAMES: &[(&str, Action)] = &[
+    ("quit", Action::Quit),
+    ("toggle-profiler", Action::ToggleProfiler),
+    ("pause-animations", Action::PauseAnimations),
+    ("copy", Action::Copy),
+    ("copy-markdown", Action::CopyMarkdown),
+    ("find", Action::Find),
+    ("line-up", Action::LineUp),
+    ("line-down", Action::LineDown),
+    ("page-up", Action::PageUp),
+    ("page-down", Action::PageDown),
+    ("top", Action::Top),
+    ("bottom", Action::Bottom),
+    ("previous-heading", Action::PreviousHeading),
+    ("next-heading", Action::NextHeading),
+    ("reload", Action::Reload),
+    ("zoom-in", Action::ZoomIn),
+    ("zoom-out", Action::ZoomOut),
+    ("reset-zoom", Action::ResetZoom),
+];
+
+/// Names keys go by in chords, besides letters, digits and F1 to F12
+const KEY_NAMES: &[(&str, VirtualKeyCode)] = &[
+    ("escape", VirtualKeyCode::Escape),
+    ("esc", VirtualKeyCode::Escape),
+    ("space", VirtualKeyCode::Space),
+    ("enter", VirtualKeyCode::Return),
+    ("return", VirtualKeyCode::Return),
+    ("tab", VirtualKeyCode::Tab),
+    ("backspace", VirtualKeyCode::Back),
+    ("insert", VirtualKeyCode::Insert),
+    ("delete", VirtualKeyCode::Delete),
+    ("up", VirtualKeyCode::Up),
+    ("down", VirtualKeyCode::Down),
+    ("left", VirtualKeyCode::Left),
+    ("right", VirtualKeyCode::Right),
+    ("pageup", VirtualKeyCode::PageUp),
+    ("pagedown", VirtualKeyCode::PageDown),
+    ("home", VirtualKeyCode::Home),
+    ("end", VirtualKeyCode::End),
+    ("[", VirtualKeyCode::LBracket),
+    ("]", VirtualKeyCode::RBracket),
+    ("-", VirtualKeyCode::Minus),
+    ("minus", VirtualKeyCode::Minus),
+    ("=", VirtualKeyCode::Equals),
+    ("equals", VirtualKeyCode::Equals),
+    ("plus", VirtualKeyCode::Add),
+    (",", VirtualKeyCode::Comma),
+    (".", VirtualKeyCode::Period),
+    ("/", VirtualKeyCode::Slash),
+    ("\\", VirtualKeyCode::Backslash),
+    (";", VirtualKeyCode::Semicolon),
+    ("'", VirtualKeyCode::Apostrophe),
+    ("`", VirtualKeyCode::Grave),
+];
+
+const LETTERS: [VirtualKeyCode; 26] = {
+    use glutin::VirtualKeyCode::*;
+    [A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z]
+};
+
+const DIGITS: [VirtualKeyCode; 10] = {
+    use glutin::VirtualKeyCode::*;
+    [Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9]
+};
+
+const FUNCTION_KEYS: [VirtualKeyCode; 12] = {
+    use glutin::VirtualKeyCode::*;
+    [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12]
+};
+
+impl Action {
+    pub fn from_name(name: &str) -> Option<Action> {
+        ACTION_NAMES.iter().find(|&&(known, _)| known == name).map(|&(_, action)| action)
+    }
+}
+
+/// A key along with the modifiers held with it. Ctrl and Cmd are the same
+/// chord, so shortcuts work the same way on every platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Chord {
+    pub key: VirtualKeyCode,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
+}
+
+impl Chord {
+    pub fn new(key: VirtualKeyCode, modifiers: ModifiersState) -> Chord {
+        Chord { key, ctrl: modifiers.ctrl || modifiers.logo, shift: modifiers.shift, alt: modifiers.alt }
+    }
+
+    /// Parses chords like `ctrl+shift+c`, `g` or `pagedown`. Case doesn't
+    /// matter, so `G` is the same as `g` and Shift has to be spelled out.
+    /// Cmd and Super are taken as Ctrl like they are for keys pressed.
+    pub fn parse(source: &str) -> Result<Chord, String> {
+        let lower = source.trim().to_lowercase();
+        let mut parts: Vec<&str> = lower.split('+').map(|part| part.trim()).collect();
+        let key_name = parts.pop().unwrap_or("");
+        let key = parse_key(key_name).ok_or_else(|| {
+            if key_name.is_empty() {
+                format!("{:?} doesn't end in a key", source)
+            } else {
+                format!("unknown key {:?} in {:?}", key_name, source)
+            }
+        })?;
+
+        let mut chord = Chord::plain(key);
+        for modifier in parts {
+            let held = match modifier {
+                "ctrl" | "control" | "cmd" | "command" | "super" => &mut chord.ctrl,
+                "shift" => &mut chord.shift,
+                "alt" | "option" => &mut chord.alt,
+                _ => return Err(format!("unknown modifier {:?} in {:?}", modifier, source)),
+            };
+            if *held {
+                return Err(format!("{} is in {:?} twice", modifier, source));
+            }
+            *held = true;
+        }
+        Ok(chord)
+    }
+
+    fn plain(key: VirtualKeyCode) -> Chord {
+        Chord { key, ctrl: false, shift: false, alt: false }
+    }
+
+    fn shift(key: VirtualKeyCode) -> Chord {
+        Chord { shift: true, ..Chord::plain(key) }
+    }
+
+    fn ctrl(key: VirtualKeyCode) -> Chord {
+        Chord { ctrl: true, ..Chord::plain(key) }
+    }
+}
+
+fn parse_key(name: &str) -> Option<VirtualKeyCode> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        if c.is_ascii_lowercase() {
+            return Some(LETTERS[(c as u8 - b'a') as usize]);
+        }
+        if let Some(digit) = c.to_digit(10) {
+            return Some(DIGITS[digit as usize]);
+        }
+    }
+    if let Some(number) = name.strip_prefix('f').and_then(|number| number.parse::<usize>().ok()) {
+        return FUNCTION_KEYS.get(number.wrapping_sub(1)).cloned();
+    }
+    KEY_NAMES.iter().find(|&&(known, _)| known == name).map(|&(_, key)| key)
+}
+
+/// Where the key bindings are read from, `QUICKDOWN_KEYS` or `keys.toml` in
+/// the `quickdown` config directory
+pub fn config_path() -> Option<PathBuf> {
+    match env::var_os("QUICKDOWN_KEYS") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::config_dir().map(|dir| dir.join("quickdown").join("keys.toml")),
+    }
+}
+
+pub struct Keymap {
+    bindings: Vec<(Chord, Action)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        use self::Action::*;
+        use glutin::VirtualKeyCode as Key;
+        Keymap {
+            bindings: vec![
+                (Chord::plain(Key::Escape), Quit),
+                (Chord::plain(Key::R), ToggleProfiler),
+                (Chord::plain(Key::P), PauseAnimations),
+                (Chord::ctrl(Key::C), Copy),
+                (Chord { shift: true, ..Chord::ctrl(Key::C) }, CopyMarkdown),
+                (Chord::ctrl(Key::F), Find),
+                (Chord::plain(Key::Up), LineUp),
+                (Chord::plain(Key::K), LineUp),
+                (Chord::plain(Key::Down), LineDown),
+                (Chord::plain(Key::J), LineDown),
+                (Chord::plain(Key::PageUp), PageUp),
+                (Chord::shift(Key::Space), PageUp),
+                (Chord::plain(Key::PageDown), PageDown),
+                (Chord::plain(Key::Space), PageDown),
+                (Chord::plain(Key::Home), Top),
+                (Chord::plain(Key::G), Top),
+                (Chord::plain(Key::End), Bottom),
+                (Chord::shift(Key::G), Bottom),
+                (Chord::plain(Key::LBracket), PreviousHeading),
+                (Chord::plain(Key::RBracket), NextHeading),
+                (Chord::plain(Key::F5), Reload),
+                (Chord::ctrl(Key::Equals), ZoomIn),
+                (Chord::ctrl(Key::Add), ZoomIn),
+                (Chord::ctrl(Key::Minus), ZoomOut),
+                (Chord::ctrl(Key::Subtract), ZoomOut),
+                (Chord::ctrl(Key::Key0), ResetZoom),
+            ],
+        }
+    }
+}
+
+impl Keymap {
+    /// The default bindings with the file at `path` laid over them, if
+    /// there is one. Bindings in it that don't make sense are left out and
+    /// what was wrong with them returned, so one mistake doesn't lose the rest.
+    pub fn load(path: &Path) -> (Keymap, Vec<String>) {
+        match fs::read_to_string(path) {
+            Ok(source) => Keymap::with_config(&source),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => (Keymap::default(), Vec::new()),
+            Err(err) => (Keymap::default(), vec![err.to_string()]),
+        }
+    }
+
+    /// The default bindings changed by a config file's worth of TOML. An
+    /// action of `"none"` unbinds the chord.
+    pub fn with_config(source: &str) -> (Keymap, Vec<String>) {
+        let mut keymap = Keymap::default();
+        let table = match source.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(err) => return (keymap, vec![err.to_string()]),
+        };
+
+        let mut errors = Vec::new();
+        let mut bound: Vec<Chord> = Vec::new();
+        for (chord_source, value) in &table {
+            let chord = match Chord::parse(chord_source) {
+                Ok(chord) => chord,
+                Err(err) => {
+                    errors.push(err);
+                    continue;
+                }
+            };
+            let action = match value.as_str() {
+                Some("none") => None,
+                Some(name) => match Action::from_name(name) {
+                    Some(action) => Some(action),
+                    None => {
+                        errors.push(format!("unknown action {:?} for {:?}", name, chord_source));
+                        continue;
+                    }
+                },
+                None => {
+                    errors.push(format!("the action for {:?} should be a name in quotes", chord_source));
+                    continue;
+                }
+            };
+            if bound.contains(&chord) {
+                errors.push(format!("{:?} is bound more than once", chord_source));
+                continue;
+            }
+            bound.push(chord);
+            keymap.bindings.retain(|&(existing, _)| existing != chord);
+            if let Some(action) = action {
+                keymap.bindings.push((chord, action));
+            }
+        }
+        (keymap, errors)
+    }
+
+    pub fn action(&self, key: VirtualKeyCode, modifiers: ModifiersState) -> Option<Action> {
+        let chord = Chord::new(key, modifiers);
+        self.bindings.iter().find(|&&(bound, _)| bound == chord).map(|&(_, action)| action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modifiers(ctrl: bool, shift: bool, logo: bool) -> ModifiersState {
+        ModifiersState { ctrl, shift, logo, alt: false }
+    }
+
+    #[test]
+    fn modifiers_must_match() {
+        let keymap = Keymap::default();
+        assert_eq!(keymap.action(VirtualKeyCode::G, modifiers(false, false, false)), Some(Action::Top));
+        assert_eq!(keymap.action(VirtualKeyCode::G, modifiers(false, true, false)), Some(Action::Bottom));
+        assert_eq!(keymap.action(VirtualKeyCode::G, modifiers(true, false, false)), None);
+        // Cmd is taken as Ctrl
+        assert_eq!(keymap.action(VirtualKeyCode::C, modifiers(false, false, true)), Some(Action::Copy));
+        assert_eq!(keymap.action(VirtualKeyCode::C, modifiers(true, true, false)), Some(Action::CopyMarkdown));
+        assert_eq!(keymap.action(VirtualKeyCode::C, modifiers(false, false, false)), None);
+    }
+
+    #[test]
+    fn parses_chords() {
+        assert_eq!(Chord::parse("ctrl+shift+c"), Ok(Chord { shift: true, ..Chord::ctrl(VirtualKeyCode::C) }));
+        assert_eq!(Chord::parse(" Cmd + Q "), Ok(Chord::ctrl(VirtualKeyCode::Q)));
+        assert_eq!(Chord::parse("G"), Ok(Chord::plain(VirtualKeyCode::G)));
+        assert_eq!(Chord::parse("alt+7"), Ok(Chord { alt: true, ..Chord::plain(VirtualKeyCode::Key7) }));
+        assert_eq!(Chord::parse("shift+space"), Ok(Chord::shift(VirtualKeyCode::Space)));
+        assert_eq!(Chord::parse("ctrl+-"), Ok(Chord::ctrl(VirtualKeyCode::Minus)));
+        assert_eq!(Chord::parse("]"), Ok(Chord::plain(VirtualKeyCode::RBracket)));
+        assert_eq!(Chord::parse("F12"), Ok(Chord::plain(VirtualKeyCode::F12)));
+    }
+
+    #[test]
+    fn rejects_bad_chords() {
+        assert_eq!(Chord::parse("hyper+x"), Err("unknown modifier \"hyper\" in \"hyper+x\"".to_string()));
+        assert_eq!(Chord::parse("ctrl+ctrl+x"), Err("ctrl is in \"ctrl+ctrl+x\" twice".to_string()));
+        assert_eq!(Chord::parse("cmd+control+x"), Err("control is in \"cmd+control+x\" twice".to_string()));
+        assert_eq!(Chord::parse("ctrl+"), Err("\"ctrl+\" doesn't end in a key".to_string()));
+        assert_eq!(Chord::parse(""), Err("\"\" doesn't end in a key".to_string()));
+        assert_eq!(Chord::parse("shift+enterr"), Err("unknown key \"enterr\" in \"shift+enterr\"".to_string()));
+        assert!(Chord::parse("f0").is_err());
+        assert!(Chord::parse("f13").is_err());
+        assert!(Chord::parse("ctrl+é").is_err());
+    }
+
+    #[test]
+    fn config_overrides_defaults() {
+        let none = modifiers(false, false, false);
+        let (keymap, errors) = Keymap::with_config("escape = \"none\"\nq = \"quit\"\nj = \"page-down\"\n");
+        assert!(errors.is_empty());
+        assert_eq!(keymap.action(VirtualKeyCode::Escape, none), None);
+        assert_eq!(keymap.action(VirtualKeyCode::Q, none), Some(Action::Quit));
+        assert_eq!(keymap.action(VirtualKeyCode::J, none), Some(Action::PageDown));
+        assert_eq!(keymap.action(VirtualKeyCode::K, none), Some(Action::LineUp));
+    }
+
+    #[test]
+    fn config_errors_skip_just_that_binding() {
+        let source = "q = \"quit\"\n\"hyper+x\" = \"quit\"\nx = \"explode\"\ny = 3\n\"ctrl+w\" = \"quit\"\n\"Ctrl+W\" = \"find\"\n";
+        let (keymap, errors) = Keymap::with_config(source);
+        assert_eq!(errors, vec![
+            "unknown modifier \"hyper\" in \"hyper+x\"".to_string(),
+            "unknown action \"explode\" for \"x\"".to_string(),
+            "the action for \"y\" should be a name in quotes".to_string(),
+            "\"Ctrl+W\" is bound more than once".to_string(),
+        ]);
+        assert_eq!(keymap.action(VirtualKeyCode::Q, modifiers(false, false, false)), Some(Action::Quit));
+        assert_eq!(keymap.action(VirtualKeyCode::W, modifiers(true, false, false)), Some(Action::Quit));
+
+        let (keymap, errors) = Keymap::with_config("q = ");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(keymap.action(VirtualKeyCode::Escape, modifiers(false, false, false)), Some(Action::Quit));
+    }
+}
@@ -0,0 +1,1031 @@
+//! Breaks text into lines and positions blocks without touching Webrender, so
+//! documents can be measured and tested without a window. Fonts are only seen
+//! through `FontMetrics`, `style` turns the result into display items.
+
+use fasternet_common::*;
+use shaping::ShapedGlyph;
+use std::f32;
+use std::ops::Range;
+
+pub const QUOTE_INDENT: f32 = 16.0;
+pub const LIST_INDENT: f32 = 36.0;
+pub const MARKER_GAP: f32 = 8.0;
+pub const CELL_PADDING: f32 = 6.0;
+
+pub const PADDING: f32 = 20.0;
+pub const LIST_PADDING: f32 = 6.0;
+pub const TOP_MARGIN: f32 = 10.0;
+
+pub trait FontMetrics {
+    /// Shapes a run of text in the font used for `kind`
+    fn shape(&self, kind: TextKind, text: &str) -> Vec<ShapedGlyph>;
+    /// Font size in pixels
+    fn font_size(&self, kind: TextKind) -> f32;
+    fn line_height(&self, kind: TextKind) -> f32;
+    /// How far below the baseline glyphs reach, in pixels
+    fn descent(&self, kind: TextKind) -> f32;
+    /// Natural size of an inline image, if it's known yet. Inline images
+    /// sit on lines like words so they're measured along with the fonts.
+    fn image_size(&self, _path: &str) -> Option<(f32, f32)> {
+        None
+    }
+    fn code_style(&self) -> CodeStyle {
+        CodeStyle::default()
+    }
+}
+
+/// What happens to lines of code too long for the column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodeWrap {
+    /// Lines are never broken and the block scrolls sideways instead
+    Scroll,
+    /// Lines are broken where they fill up, with a marker at the end of each broken line
+    Wrap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodeStyle {
+    /// Spaces between tab stops
+    pub tab_width: u32,
+    pub wrap: CodeWrap,
+}
+
+impl Default for CodeStyle {
+    fn default() -> CodeStyle {
+        CodeStyle { tab_width: 4, wrap: CodeWrap::Scroll }
+    }
+}
+
+/// Part of a single line in a single style
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    /// Indexes into the glyphs of the `TextLayout`
+    pub glyphs: Range<usize>,
+    pub kind: TextKind,
+    pub link: Option<usize>,
+    /// Left edge relative to the block, including the indent
+    pub x: f32,
+    pub baseline: f32,
+    pub width: f32,
+    pub height: f32,
+    /// How far the glyphs reach below the baseline
+    pub descent: f32,
+}
+
+impl Run {
+    pub fn top(&self) -> f32 {
+        self.baseline - self.height
+    }
+
+    /// The bottom of the lowest descender
+    pub fn bottom(&self) -> f32 {
+        self.baseline + self.descent
+    }
+
+    /// Whether a point relative to the block is over the run
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.x && x < self.x + self.width && y >= self.top() && y < self.bottom()
+    }
+}
+
+/// An inline image, scaled to the height of the line it sits on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Atom {
+    pub path: String,
+    /// Whether the image's size was known, otherwise it's a square until it is
+    pub sized: bool,
+    pub link: Option<usize>,
+    /// Top left relative to the block
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub struct TextLayout {
+    pub glyphs: Vec<u32>,
+    pub advances: Vec<f32>,
+    /// How far each glyph is nudged from where the advances put it
+    pub offsets: Vec<(f32, f32)>,
+    /// Byte offset in the block's text of the first char each glyph was made from
+    pub clusters: Vec<usize>,
+    /// Length in bytes of the block's text
+    pub text_len: usize,
+    pub runs: Vec<Run>,
+    pub atoms: Vec<Atom>,
+    pub width: f32,
+    pub height: f32,
+    /// Width of the longest line, not counting the indent
+    pub content_width: f32,
+    pub indent: f32,
+    pub bg: BlockBackground,
+    pub quote_depth: u8,
+    pub list_level: u8,
+    /// Bullet or number hanging in the gutter left of the first line, with its left edge
+    pub marker: Option<(Box<TextLayout>, f32)>,
+    /// Where the marker goes on each wrapped line of code, as its left edge and baseline
+    pub continuations: Vec<(f32, f32)>,
+    pub links: Vec<Link>,
+}
+
+pub struct TableLayout {
+    /// The header is the first row
+    pub rows: Vec<Vec<TextLayout>>,
+    pub alignments: Vec<Alignment>,
+    pub col_widths: Vec<f32>,
+    pub row_heights: Vec<f32>,
+    pub quote_depth: u8,
+    /// How far the grid sits from the left edge of the column
+    pub indent: f32,
+    /// The width of the grid, not counting the indent
+    pub width: f32,
+    pub height: f32,
+}
+
+#[derive(Debug)]
+enum Token {
+    /// Length in bytes
+    Word(usize),
+    Space,
+    Tab,
+    Newline,
+    End,
+}
+
+/// Accumulates runs for one block while its chunks are broken into lines
+struct LineBreaker {
+    runs: Vec<Run>,
+    atoms: Vec<Atom>,
+    /// Left edge of the text, every line starts here
+    left: f32,
+    width: f32,
+    x: f32,
+    /// Baseline of the current line
+    baseline: f32,
+    content_width: f32,
+    /// The next run starts a new line
+    newline: bool,
+    kind: TextKind,
+    link: Option<usize>,
+    line_height: f32,
+    descent: f32,
+    /// Index in the block of the first glyph of the current chunk
+    glyph_base: usize,
+    /// Current run, relative to the chunk
+    run_glyphs: Range<usize>,
+    /// How much of the chunk has been broken up
+    byte: usize,
+    run_x: f32,
+    run_width: f32,
+    /// Distance between tab stops, tabs are just wide spaces when it's zero
+    tab_stop: f32,
+    /// Whether lines broken for being too long are marked, which is done for code
+    mark_wraps: bool,
+    continuations: Vec<(f32, f32)>,
+}
+
+impl LineBreaker {
+    fn flush(&mut self) {
+        if self.run_glyphs.start != self.run_glyphs.end {
+            if self.newline {
+                self.baseline += self.line_height;
+            }
+            self.runs.push(Run {
+                glyphs: (self.glyph_base + self.run_glyphs.start)..(self.glyph_base + self.run_glyphs.end),
+                kind: self.kind,
+                link: self.link,
+                x: self.left + self.run_x,
+                baseline: self.baseline,
+                width: self.run_width,
+                height: self.line_height,
+                descent: self.descent,
+            });
+            self.newline = false;
+        }
+        self.run_glyphs.start = self.run_glyphs.end;
+        self.run_x = self.x;
+        self.run_width = 0.0;
+    }
+
+    fn break_line(&mut self) {
+        self.flush();
+        self.x = 0.0;
+        self.run_x = 0.0;
+        self.newline = true;
+    }
+
+    /// Breaks a line for being too long rather than at a newline
+    fn wrap(&mut self) {
+        // flushed first so the baseline is the one of the line being broken
+        self.flush();
+        if self.mark_wraps && self.x > 0.0 {
+            self.continuations.push((self.left + self.width, self.baseline));
+        }
+        self.break_line();
+    }
+
+    /// Places an inline image like a word, `size` is how big it would be unscaled
+    fn atom(&mut self, path: &str, size: Option<(f32, f32)>) {
+        let height = self.line_height;
+        let width = match size {
+            Some((width, natural_height)) if natural_height > 0.0 => (width * height / natural_height).min(self.width),
+            _ => height,
+        };
+        if self.x + width > self.width {
+            self.wrap();
+        }
+        if self.newline {
+            self.baseline += self.line_height;
+            self.newline = false;
+        }
+        self.atoms.push(Atom {
+            path: path.to_string(),
+            sized: size.is_some(),
+            link: self.link,
+            x: self.left + self.x,
+            // TODO sit on the baseline rather than filling the line
+            y: self.baseline - height,
+            width, height,
+        });
+        self.x += width;
+        self.run_x = self.x;
+        self.content_width = self.content_width.max(self.x);
+    }
+
+    /// Consumes the glyphs made from text before byte `end` of the chunk, returning their width.
+    /// Clusters only ever increase since we never shape right to left text.
+    fn take_glyphs(&mut self, glyphs: &[ShapedGlyph], end: usize) -> f32 {
+        let mut width = 0.0;
+        let mut next = self.run_glyphs.end;
+        while next < glyphs.len() && glyphs[next].cluster < end {
+            width += glyphs[next].advance;
+            next += 1;
+        }
+        self.run_glyphs.end = next;
+        width
+    }
+
+    /// Adds the glyphs of a word too long for a line of its own, breaking it wherever lines fill up
+    fn split_word(&mut self, word_glyphs: Range<usize>, glyphs: &[ShapedGlyph]) {
+        for glyph in &glyphs[word_glyphs] {
+            if self.x + glyph.advance > self.width && self.x > 0.0 {
+                self.wrap();
+            }
+            self.run_glyphs.end += 1;
+            self.x += glyph.advance;
+            self.run_width += glyph.advance;
+            self.content_width = self.content_width.max(self.x);
+        }
+    }
+
+    /// Breaks a chunk into runs. Tab glyphs get the advance that takes them to the next tab stop.
+    fn chunk(&mut self, chunk_str: &str, glyphs: &mut [ShapedGlyph]) {
+        for token in tokenize(chunk_str) {
+            let start = self.byte;
+            match token {
+                Token::Word(len) => {
+                    let run_glyphs = self.run_glyphs.end;
+                    let word_width = self.take_glyphs(glyphs, start + len);
+                    if self.x + word_width > self.width {
+                        // the word goes on the next line, so leave its glyphs out of this run
+                        let word_glyphs = run_glyphs..self.run_glyphs.end;
+                        self.run_glyphs.end = word_glyphs.start;
+                        if self.mark_wraps && word_width > self.width {
+                            self.split_word(word_glyphs, glyphs);
+                        } else {
+                            self.wrap();
+                            self.run_glyphs.end = word_glyphs.end;
+                            self.x += word_width;
+                            self.run_width += word_width;
+                        }
+                    } else {
+                        self.x += word_width;
+                        self.run_width += word_width;
+                    }
+                    self.byte += len;
+                    self.content_width = self.content_width.max(self.x);
+                }
+                Token::Space => {
+                    let space_width = self.take_glyphs(glyphs, start + 1);
+                    self.byte += 1;
+                    self.x += space_width;
+                    self.run_width += space_width;
+                }
+                Token::Tab => {
+                    let tab_glyphs = self.run_glyphs.end;
+                    let mut tab_width = self.take_glyphs(glyphs, start + 1);
+                    if self.tab_stop > 0.0 {
+                        tab_width = ((self.x / self.tab_stop).floor() + 1.0) * self.tab_stop - self.x;
+                        for (i, glyph) in glyphs[tab_glyphs..self.run_glyphs.end].iter_mut().enumerate() {
+                            glyph.advance = if i == 0 { tab_width } else { 0.0 };
+                        }
+                    }
+                    self.byte += 1;
+                    self.x += tab_width;
+                    self.run_width += tab_width;
+                }
+                Token::Newline => {
+                    self.take_glyphs(glyphs, start + 1);
+                    self.byte += 1;
+                    self.break_line();
+                }
+                Token::End => self.flush(),
+            }
+        }
+    }
+}
+
+fn tokenize(chunk_str: &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut word_len = 0;
+    for c in chunk_str.chars() {
+        match c {
+            ' ' | '\t' | '\n' => {
+                if word_len > 0 { tokens.push(Token::Word(word_len)) }
+                word_len = 0;
+                tokens.push(match c {
+                    ' ' => Token::Space,
+                    '\t' => Token::Tab,
+                    _ => Token::Newline,
+                });
+            }
+            _ => word_len += c.len_utf8(),
+        }
+    }
+    if word_len > 0 { tokens.push(Token::Word(word_len)) }
+    tokens.push(Token::End);
+    tokens
+}
+
+pub fn indent_for(quote_depth: u8, list_level: u8) -> f32 {
+    (quote_depth as f32) * QUOTE_INDENT + (list_level as f32) * LIST_INDENT
+}
+
+/// Breaks a block into lines that fit in `width`, including its indent.
+/// Code blocks keep their lines as they are, unless the theme wraps them.
+pub fn layout_text<M: FontMetrics>(block: &TextBlock, metrics: &M, width: f32) -> TextLayout {
+    let indent = indent_for(block.quote_depth, block.list_level);
+    let is_code = block.bg == BlockBackground::Code;
+    let code_style = metrics.code_style();
+    let space = if is_code {
+        metrics.shape(TextKind::CodeBlock, " ").first().map_or(0.0, |glyph| glyph.advance)
+    } else {
+        0.0
+    };
+    let (line_width, mark_wraps) = match code_style.wrap {
+        _ if !is_code => (width - indent, false),
+        CodeWrap::Scroll => (f32::INFINITY, false),
+        // a monospace marker takes up as much room as a space at the end of the line
+        CodeWrap::Wrap => (width - indent - space, true),
+    };
+    let mut glyphs = Vec::with_capacity(block.content.len());
+    let mut advances = Vec::with_capacity(block.content.len());
+    let mut offsets = Vec::with_capacity(block.content.len());
+    let mut clusters = Vec::with_capacity(block.content.len());
+
+    let mut breaker = LineBreaker {
+        runs: Vec::with_capacity(block.chunks.len()),
+        atoms: Vec::new(),
+        left: indent,
+        width: line_width,
+        x: 0.0,
+        baseline: 0.0,
+        content_width: 0.0,
+        newline: true,
+        kind: TextKind::Paragraph,
+        link: None,
+        line_height: 0.0,
+        descent: 0.0,
+        glyph_base: 0,
+        run_glyphs: 0..0,
+        byte: 0,
+        run_x: 0.0,
+        run_width: 0.0,
+        tab_stop: space * code_style.tab_width as f32,
+        mark_wraps,
+        continuations: Vec::new(),
+    };
+
+    let mut descent = 0.0;
+    let mut images = block.images.iter();
+    for (byte_range, chunk) in block.chunk_ranges() {
+        let chunk_str = &block.content[byte_range.clone()];
+        if chunk.kind == TextKind::InlineImage {
+            breaker.link = chunk.link.map(|link| link as usize);
+            breaker.line_height = metrics.line_height(chunk.kind);
+            breaker.descent = metrics.descent(chunk.kind);
+            for image in chunk_str.chars().filter_map(|_| images.next()) {
+                breaker.atom(&image.path, metrics.image_size(&image.path));
+            }
+            if glyphs.is_empty() {
+                descent = metrics.descent(chunk.kind);
+            }
+            continue;
+        }
+
+        // even if this gets split, the whole thing is still the same font
+        let mut shaped = metrics.shape(chunk.kind, chunk_str);
+        let glyph_start = glyphs.len();
+        breaker.glyph_base = glyph_start;
+        glyphs.extend(shaped.iter().map(|glyph| glyph.glyph));
+        offsets.extend(shaped.iter().map(|glyph| (glyph.x_offset, glyph.y_offset)));
+        clusters.extend(shaped.iter().map(|glyph| byte_range.start + glyph.cluster));
+
+        breaker.run_glyphs = 0..0;
+        breaker.byte = 0;
+        breaker.run_x = breaker.x;
+        breaker.kind = chunk.kind;
+        breaker.link = chunk.link.map(|link| link as usize);
+        breaker.line_height = metrics.line_height(chunk.kind);
+        breaker.descent = metrics.descent(chunk.kind);
+        breaker.chunk(chunk_str, &mut shaped);
+        advances.extend(shaped.iter().map(|glyph| glyph.advance));
+
+        if glyph_start == 0 {
+            descent = metrics.descent(chunk.kind);
+        }
+    }
+
+    let marker = block.list_marker.map(|marker| {
+        let marker = layout_text(&marker_block(&marker), metrics, LIST_INDENT);
+        let left = indent - MARKER_GAP - marker.content_width;
+        (Box::new(marker), left)
+    });
+    TextLayout {
+        glyphs, advances, offsets, clusters, indent, width, marker,
+        text_len: block.content.len(),
+        runs: breaker.runs,
+        atoms: breaker.atoms,
+        height: breaker.baseline + descent,
+        content_width: breaker.content_width,
+        continuations: breaker.continuations,
+        bg: block.bg,
+        quote_depth: block.quote_depth,
+        list_level: block.list_level,
+        links: block.links.clone(),
+    }
+}
+
+fn marker_block(marker: &ListMarker) -> TextBlock {
+    let content = match (marker.checked, marker.kind) {
+        (Some(true), _) => "[x]".to_string(),
+        (Some(false), _) => "[ ]".to_string(),
+        (None, ListKind::Bullet) => "\u{2022}".to_string(),
+        (None, ListKind::Ordered) => format!("{}.", marker.number),
+    };
+    plain_block(content, TextKind::Paragraph)
+}
+
+/// A block of text in a single style, for things we show that aren't in the document
+pub fn plain_block(content: String, kind: TextKind) -> TextBlock {
+    let mut chunks = Vec::new();
+    Chunk::push_run(&mut chunks, &content, kind, None);
+    TextBlock {
+        content, chunks,
+        links: Vec::new(),
+        bg: BlockBackground::NoBackground,
+        quote_depth: 0,
+        list_level: 0,
+        list_marker: None,
+        images: Vec::new(),
+        language: None,
+        heading_level: None,
+    }
+}
+
+impl TextLayout {
+    pub fn in_list(&self) -> bool {
+        self.list_level > 0
+    }
+
+    /// The byte offset in the block's text closest to a point relative to the
+    /// block, for where a selection starts or ends
+    pub fn position_at(&self, x: f32, y: f32) -> usize {
+        if y < 0.0 {
+            return 0;
+        }
+        let line = match self.runs.iter().find(|run| y < run.bottom()) {
+            Some(run) => run.baseline,
+            None => return self.text_len,
+        };
+        for run in self.runs.iter().filter(|run| run.baseline == line) {
+            let mut glyph_x = run.x;
+            for i in run.glyphs.clone() {
+                if x < glyph_x + self.advances[i] / 2.0 {
+                    return self.clusters[i];
+                }
+                glyph_x += self.advances[i];
+            }
+        }
+        // past the end of the line, which is where the next one starts
+        self.runs.iter()
+            .find(|run| run.baseline > line)
+            .map_or(self.text_len, |run| self.clusters[run.glyphs.start])
+    }
+
+    /// Rectangles covering the glyphs made from a range of the block's text,
+    /// one per run, as their left, top, width and height
+    pub fn range_rects(&self, range: Range<usize>) -> Vec<(f32, f32, f32, f32)> {
+        let mut rects = Vec::new();
+        for run in &self.runs {
+            let mut x = run.x;
+            let mut selected: Option<(f32, f32)> = None;
+            for i in run.glyphs.clone() {
+                let advance = self.advances[i];
+                if range.contains(&self.clusters[i]) {
+                    selected = Some((selected.map_or(x, |(start, _)| start), x + advance));
+                }
+                x += advance;
+            }
+            if let Some((start, end)) = selected {
+                rects.push((start, run.top(), end - start, run.bottom() - run.top()));
+            }
+        }
+        rects
+    }
+
+    /// How far a code block too wide for its column can be scrolled sideways
+    pub fn scroll_width(&self) -> f32 {
+        if self.bg == BlockBackground::Code {
+            (self.content_width - (self.width - self.indent)).max(0.0)
+        } else {
+            0.0
+        }
+    }
+
+    /// Finds the link under a point relative to the top left of the block
+    pub fn link_at(&self, x: f32, y: f32) -> Option<&Link> {
+        let in_atom = |atom: &&Atom| x >= atom.x && x < atom.x + atom.width && y >= atom.y && y < atom.y + atom.height;
+        self.runs.iter()
+            .filter(|run| run.contains(x, y))
+            .filter_map(|run| run.link)
+            .chain(self.atoms.iter().filter(in_atom).filter_map(|atom| atom.link))
+            .next()
+            .and_then(|link| self.links.get(link))
+    }
+}
+
+/// The width a block would take up if it was never wrapped
+pub fn natural_width<M: FontMetrics>(block: &TextBlock, metrics: &M) -> f32 {
+    layout_text(block, metrics, f32::INFINITY).content_width
+}
+
+pub fn layout_table<M: FontMetrics>(table: &TableBlock, metrics: &M, width: f32) -> TableLayout {
+    let cols = table.alignments.len();
+    let source_rows: Vec<&Vec<TextBlock>> = Some(&table.header).into_iter().chain(table.rows.iter()).collect();
+
+    let mut natural = vec![0.0f32; cols];
+    for row in &source_rows {
+        for (col, cell) in row.iter().enumerate() {
+            natural[col] = natural[col].max(natural_width(cell, metrics));
+        }
+    }
+
+    let indent = indent_for(table.quote_depth, 0);
+    let available = width - indent - (cols as f32) * CELL_PADDING * 2.0;
+    let col_widths = column_widths(&natural, available);
+
+    let mut row_heights = Vec::with_capacity(source_rows.len());
+    let rows: Vec<Vec<TextLayout>> = source_rows.iter().map(|row| {
+        let mut row_height: f32 = 0.0;
+        let laid_out: Vec<TextLayout> = row.iter().zip(col_widths.iter()).map(|(cell, &col_width)| {
+            let cell = layout_text(cell, metrics, col_width);
+            row_height = row_height.max(cell.height);
+            cell
+        }).collect();
+        row_heights.push(row_height + CELL_PADDING * 2.0);
+        laid_out
+    }).collect();
+
+    TableLayout {
+        rows,
+        width: col_widths.iter().map(|w| w + CELL_PADDING * 2.0).sum(),
+        height: row_heights.iter().sum(),
+        alignments: table.alignments.clone(),
+        quote_depth: table.quote_depth,
+        col_widths, row_heights, indent,
+    }
+}
+
+/// Columns that fit in an even share of the space keep their natural width,
+/// the remaining space is split between the wider columns in proportion to
+/// how wide they'd like to be.
+fn column_widths(natural: &[f32], available: f32) -> Vec<f32> {
+    let total: f32 = natural.iter().sum();
+    if total <= available {
+        return natural.to_vec();
+    }
+    let share = available / (natural.len() as f32);
+    let fixed: f32 = natural.iter().filter(|&&w| w <= share).sum();
+    let flexible = total - fixed;
+    natural.iter().map(|&w| {
+        if w <= share { w } else { w * (available - fixed) / flexible }
+    }).collect()
+}
+
+impl TableLayout {
+    /// Every cell along with where its text goes, relative to the top left of the table
+    pub fn cells(&self) -> Vec<(&TextLayout, f32, f32)> {
+        let mut cells = Vec::new();
+        let mut y = 0.0;
+        for (row, &row_height) in self.rows.iter().zip(&self.row_heights) {
+            let mut x = self.indent;
+            for ((cell, &col_width), alignment) in row.iter().zip(&self.col_widths).zip(&self.alignments) {
+                let slack = (col_width - cell.content_width).max(0.0);
+                let offset = match *alignment {
+                    Alignment::None | Alignment::Left => 0.0,
+                    Alignment::Center => slack / 2.0,
+                    Alignment::Right => slack,
+                };
+                cells.push((cell, x + CELL_PADDING + offset, y + CELL_PADDING));
+                x += col_width + CELL_PADDING * 2.0;
+            }
+            y += row_height;
+        }
+        cells
+    }
+
+    pub fn link_at(&self, x: f32, y: f32) -> Option<&Link> {
+        self.cells().into_iter()
+            .filter_map(|(cell, cell_x, cell_y)| cell.link_at(x - cell_x, y - cell_y))
+            .next()
+    }
+}
+
+/// Stacks blocks vertically given the height of each and whether it's a list
+/// item, returning the top of each block and the total height.
+pub fn stack_blocks(blocks: &[(f32, bool)]) -> (Vec<f32>, f32) {
+    let mut y = TOP_MARGIN;
+    let tops = blocks.iter().enumerate().map(|(i, &(height, in_list))| {
+        let top = y;
+        // consecutive list items are packed closer together than other blocks
+        let next_in_list = blocks.get(i + 1).map(|&(_, next)| next) == Some(true);
+        y += height + if in_list && next_in_list { LIST_PADDING } else { PADDING };
+        top
+    }).collect();
+    (tops, y)
+}
+
+/// How many image pixels make up a layout pixel, from an `@2x` style suffix on the file name
+pub fn pixel_density(path: &str) -> f32 {
+    let name = path.split(['?', '#']).next().unwrap_or(path);
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let stem = name.rfind('.').map_or(name, |dot| &name[..dot]);
+    let density = match stem.rfind('@') {
+        Some(at) if stem.ends_with('x') => stem[at + 1..stem.len() - 1].parse().ok(),
+        _ => None,
+    };
+    density.filter(|&density: &f32| density > 0.0).unwrap_or(1.0)
+}
+
+/// Size a block level image is drawn at: its natural size, or what its HTML
+/// asks for, scaled down to fit `max_width` but never up
+pub fn image_display_size(image: &ImageBlock, natural: (f32, f32), max_width: f32) -> (f32, f32) {
+    let density = pixel_density(&image.path);
+    let (natural_width, natural_height) = (natural.0 / density, natural.1 / density);
+    let (width, height) = match (image.width, image.height) {
+        (Some(width), Some(height)) => (width as f32, height as f32),
+        (Some(width), None) => (width as f32, width as f32 * natural_height / natural_width),
+        (None, Some(height)) => (height as f32 * natural_width / natural_height, height as f32),
+        (None, None) => (natural_width, natural_height),
+    };
+    if width > max_width {
+        (max_width, height * max_width / width)
+    } else {
+        (width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fasternet_common::markdown::parse_markdown;
+
+    /// Every char is one glyph 10px wide, headings are twice the size
+    struct FixedMetrics;
+
+    impl FontMetrics for FixedMetrics {
+        fn shape(&self, kind: TextKind, text: &str) -> Vec<ShapedGlyph> {
+            let advance = self.font_size(kind);
+            text.char_indices().map(|(cluster, c)| {
+                ShapedGlyph { glyph: c as u32, cluster, advance, x_offset: 0.0, y_offset: 0.0 }
+            }).collect()
+        }
+
+        fn font_size(&self, kind: TextKind) -> f32 {
+            if kind.heading_level().is_some() { 20.0 } else { 10.0 }
+        }
+
+        fn line_height(&self, kind: TextKind) -> f32 {
+            self.font_size(kind) + 2.0
+        }
+
+        fn descent(&self, kind: TextKind) -> f32 {
+            self.font_size(kind) * 0.3
+        }
+
+        fn image_size(&self, path: &str) -> Option<(f32, f32)> {
+            match path {
+                "wide.png" => Some((40.0, 20.0)),
+                "huge.png" => Some((1000.0, 10.0)),
+                _ => None,
+            }
+        }
+    }
+
+    fn text_block(markdown: &str) -> TextBlock {
+        match parse_markdown(markdown).remove(0) {
+            Block::Text(text) => text,
+            block => panic!("expected text, got {:?}", block),
+        }
+    }
+
+    fn layout(markdown: &str, width: f32) -> TextLayout {
+        layout_text(&text_block(markdown), &FixedMetrics, width)
+    }
+
+    /// The text of each line, the glyphs of `FixedMetrics` are just chars
+    fn lines(layout: &TextLayout) -> Vec<String> {
+        let mut lines: Vec<(f32, String)> = Vec::new();
+        for run in &layout.runs {
+            if lines.last().map(|&(baseline, _)| baseline) != Some(run.baseline) {
+                lines.push((run.baseline, String::new()));
+            }
+            let text = layout.glyphs[run.glyphs.clone()].iter().map(|&glyph| ::std::char::from_u32(glyph).unwrap());
+            lines.last_mut().unwrap().1.extend(text);
+        }
+        lines.into_iter().map(|(_, line)| line).collect()
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        let layout = layout("aaa bbb ccc dd", 75.0);
+        assert_eq!(lines(&layout), vec!["aaa bbb ", "ccc dd"]);
+        assert_eq!(layout.content_width, 70.0);
+        assert_eq!(layout.height, 12.0 * 2.0 + 3.0);
+
+        let second = &layout.runs[1];
+        assert_eq!((second.x, second.baseline, second.width), (0.0, 24.0, 60.0));
+        assert_eq!(second.glyphs, 8..14);
+    }
+
+    #[test]
+    fn fits_exactly() {
+        let layout = layout("aaa bbb", 70.0);
+        assert_eq!(lines(&layout), vec!["aaa bbb"]);
+    }
+
+    #[test]
+    fn long_word_overflows_without_blank_line() {
+        let layout = layout("abcdefghij xy", 50.0);
+        assert_eq!(lines(&layout), vec!["abcdefghij ", "xy"]);
+        assert_eq!(layout.runs[0].baseline, 12.0);
+    }
+
+    #[test]
+    fn styles_share_lines() {
+        let layout = layout("aa **bb** cc", 1000.0);
+        assert_eq!(lines(&layout), vec!["aa bb cc"]);
+        let xs: Vec<f32> = layout.runs.iter().map(|run| run.x).collect();
+        assert_eq!(xs, vec![0.0, 30.0, 50.0]);
+        assert_eq!(layout.runs[1].kind, TextKind::ParagraphBold);
+    }
+
+    #[test]
+    fn wrap_at_style_boundary() {
+        let layout = layout("aaaa **bbbb**", 60.0);
+        assert_eq!(lines(&layout), vec!["aaaa ", "bbbb"]);
+        assert_eq!(layout.runs[1].x, 0.0);
+        assert_eq!(layout.runs[1].baseline, 24.0);
+    }
+
+    #[test]
+    fn code_block_newlines() {
+        let layout = layout("```\na\n\nb\n```", 1000.0);
+        assert_eq!(lines(&layout), vec!["a\n", "\n", "b\n"]);
+        assert_eq!(layout.height, 12.0 * 3.0 + 3.0);
+    }
+
+    /// Where a glyph starts, it must be in the layout once
+    fn glyph_x(layout: &TextLayout, c: char) -> f32 {
+        for run in &layout.runs {
+            let mut x = run.x;
+            for i in run.glyphs.clone() {
+                if layout.glyphs[i] == c as u32 {
+                    return x;
+                }
+                x += layout.advances[i];
+            }
+        }
+        panic!("{:?} isn't in the layout", c)
+    }
+
+    #[test]
+    fn code_tab_stops() {
+        let code = layout("```\nx\ta\nb\tc\nefgh\ti\n```", 1000.0);
+        assert_eq!(lines(&code), vec!["x\ta\n", "b\tc\n", "efgh\ti\n"]);
+        assert_eq!((glyph_x(&code, 'a'), glyph_x(&code, 'c'), glyph_x(&code, 'i')), (40.0, 40.0, 80.0));
+
+        // outside code tabs are spaces that happen to be tabs
+        let prose = layout_text(&plain_block("a\tb".to_string(), TextKind::Paragraph), &FixedMetrics, 1000.0);
+        assert_eq!(glyph_x(&prose, 'b'), 20.0);
+        let prose = layout_text(&plain_block("aaaa\tbbbb".to_string(), TextKind::Paragraph), &FixedMetrics, 60.0);
+        assert_eq!(lines(&prose), vec!["aaaa\t", "bbbb"]);
+    }
+
+    #[test]
+    fn code_scrolls_instead_of_wrapping() {
+        let code = layout("```\naaaa bbbb  cccc\n```", 60.0);
+        assert_eq!(lines(&code), vec!["aaaa bbbb  cccc\n"]);
+        assert_eq!(code.content_width, 150.0);
+        assert_eq!(code.scroll_width(), 90.0);
+        assert!(code.continuations.is_empty());
+        assert_eq!(layout("aaaa bbbb", 60.0).scroll_width(), 0.0);
+    }
+
+    /// Like `FixedMetrics` but with wrapped code
+    struct WrappingMetrics;
+
+    impl FontMetrics for WrappingMetrics {
+        fn shape(&self, kind: TextKind, text: &str) -> Vec<ShapedGlyph> {
+            FixedMetrics.shape(kind, text)
+        }
+
+        fn font_size(&self, kind: TextKind) -> f32 {
+            FixedMetrics.font_size(kind)
+        }
+
+        fn line_height(&self, kind: TextKind) -> f32 {
+            FixedMetrics.line_height(kind)
+        }
+
+        fn descent(&self, kind: TextKind) -> f32 {
+            FixedMetrics.descent(kind)
+        }
+
+        fn code_style(&self) -> CodeStyle {
+            CodeStyle { tab_width: 2, wrap: CodeWrap::Wrap }
+        }
+    }
+
+    #[test]
+    fn code_wraps_with_markers() {
+        // a space is left for the marker, so lines are 55px
+        let layout = layout_text(&text_block("```\naaaa bbbb\ncccccccccccc\nx\tdd\n```"), &WrappingMetrics, 65.0);
+        assert_eq!(lines(&layout), vec!["aaaa ", "bbbb\n", "ccccc", "ccccc", "cc\n", "x\tdd\n"]);
+        assert_eq!(layout.continuations, vec![(55.0, 12.0), (55.0, 36.0), (55.0, 48.0)]);
+        assert_eq!(layout.content_width, 50.0);
+        assert_eq!(glyph_x(&layout, 'd'), 20.0);
+    }
+
+    #[test]
+    fn positions_under_points() {
+        let layout = layout("aaa bbb ccc dd", 75.0);
+        assert_eq!(layout.position_at(0.0, 5.0), 0);
+        assert_eq!(layout.position_at(14.0, 5.0), 1);
+        assert_eq!(layout.position_at(16.0, 5.0), 2);
+        // the end of a line is where the next one starts
+        assert_eq!(layout.position_at(500.0, 5.0), 8);
+        assert_eq!(layout.position_at(22.0, 20.0), 10);
+        assert_eq!(layout.position_at(500.0, 20.0), 14);
+        assert_eq!(layout.position_at(50.0, -3.0), 0);
+        assert_eq!(layout.position_at(0.0, 100.0), 14);
+    }
+
+    #[test]
+    fn range_rects_per_run() {
+        let layout = layout("aaa bbb ccc dd", 75.0);
+        let height = 12.0 + 3.0;
+        assert_eq!(layout.range_rects(5..10), vec![(50.0, 0.0, 30.0, height), (0.0, 12.0, 20.0, height)]);
+        assert_eq!(layout.range_rects(0..0), vec![]);
+    }
+
+    #[test]
+    fn multibyte_text() {
+        let layout = layout("héé ünï", 50.0);
+        assert_eq!(lines(&layout), vec!["héé ", "ünï"]);
+        assert_eq!(layout.runs[1].glyphs, 4..7);
+        assert_eq!(&layout.clusters[..6], &[0, 1, 3, 5, 6, 8]);
+    }
+
+    #[test]
+    fn heading_height() {
+        let layout = layout("# Title", 1000.0);
+        assert_eq!(layout.height, 22.0 + 6.0);
+        assert_eq!(layout.content_width, 100.0);
+    }
+
+    #[test]
+    fn list_indent_and_marker() {
+        let blocks = parse_markdown("- aaa bbb\n");
+        let block = match blocks[0] {
+            Block::Text(ref text) => text.clone(),
+            _ => unreachable!(),
+        };
+        let layout = layout_text(&block, &FixedMetrics, LIST_INDENT + 50.0);
+        assert_eq!(lines(&layout), vec!["aaa ", "bbb"]);
+        assert!(layout.runs.iter().all(|run| run.x == LIST_INDENT));
+        let (ref marker, left) = *layout.marker.as_ref().unwrap();
+        assert_eq!(left, LIST_INDENT - MARKER_GAP - 10.0);
+        assert_eq!(marker.runs.len(), 1);
+    }
+
+    #[test]
+    fn links_are_hit() {
+        let layout = layout("see [here](a.md) ok", 1000.0);
+        assert_eq!(layout.link_at(45.0, 5.0).map(|link| &link.dest[..]), Some("a.md"));
+        assert!(layout.link_at(5.0, 5.0).is_none());
+        assert!(layout.link_at(45.0, 40.0).is_none());
+    }
+
+    #[test]
+    fn inline_images() {
+        let text = layout("aaa ![x](wide.png) ![y](missing.png) bb", 70.0);
+        assert_eq!(lines(&text), vec!["aaa  ", " bb"]);
+        let atoms: Vec<(f32, f32, f32, bool)> = text.atoms.iter()
+            .map(|atom| (atom.x, atom.y, atom.width, atom.sized))
+            .collect();
+        // scaled to the line height, the missing one is a square
+        assert_eq!(atoms, vec![(40.0, 0.0, 24.0, true), (0.0, 12.0, 12.0, false)]);
+        assert_eq!(text.height, 12.0 * 2.0 + 3.0);
+
+        // never wider than the line
+        let huge = layout("![x](huge.png)![x](huge.png)", 60.0);
+        assert_eq!(huge.atoms[0].width, 60.0);
+        assert_eq!(huge.atoms[1].y, 12.0);
+    }
+
+    #[test]
+    fn linked_inline_image() {
+        let layout = layout("[![badge](wide.png)](https://ci) text", 1000.0);
+        assert_eq!(layout.link_at(10.0, 5.0).map(|link| &link.dest[..]), Some("https://ci"));
+        assert!(layout.link_at(30.0, 5.0).is_none());
+    }
+
+    #[test]
+    fn table_heights() {
+        let blocks = parse_markdown("a | b\n--|--\nx | yyy yyy\n");
+        let table = match blocks[0] {
+            Block::Table(ref table) => table.clone(),
+            _ => panic!("expected a table"),
+        };
+        let layout = layout_table(&table, &FixedMetrics, 1000.0);
+        assert_eq!(layout.col_widths, vec![10.0, 70.0]);
+        let row_height = 15.0 + CELL_PADDING * 2.0;
+        assert_eq!(layout.row_heights, vec![row_height, row_height]);
+        assert_eq!(layout.height, row_height * 2.0);
+
+        // squeezing the table wraps the wide column
+        let layout = layout_table(&table, &FixedMetrics, 50.0 + CELL_PADDING * 4.0);
+        assert_eq!(layout.col_widths, vec![10.0, 40.0]);
+        assert_eq!(layout.row_heights[1], 27.0 + CELL_PADDING * 2.0);
+    }
+
+    #[test]
+    fn quoted_table_indent() {
+        let blocks = parse_markdown("> a | b\n> --|--\n> x | yyy yyy\n");
+        let table = match blocks[0] {
+            Block::Table(ref table) => table.clone(),
+            _ => panic!("expected a table"),
+        };
+        // the indent comes out of the space the columns get
+        let layout = layout_table(&table, &FixedMetrics, QUOTE_INDENT + 50.0 + CELL_PADDING * 4.0);
+        assert_eq!(layout.indent, QUOTE_INDENT);
+        assert_eq!(layout.col_widths, vec![10.0, 40.0]);
+        let (_, x, _) = layout.cells()[0];
+        assert_eq!(x, QUOTE_INDENT + CELL_PADDING);
+    }
+
+    #[test]
+    fn stacking() {
+        let (tops, total) = stack_blocks(&[(10.0, false), (5.0, true), (5.0, true), (10.0, false)]);
+        assert_eq!(tops, vec![TOP_MARGIN, TOP_MARGIN + 30.0, TOP_MARGIN + 35.0 + LIST_PADDING, TOP_MARGIN + 60.0 + LIST_PADDING]);
+        assert_eq!(total, TOP_MARGIN + 90.0 + LIST_PADDING);
+    }
+
+    fn image(path: &str, width: Option<u32>, height: Option<u32>) -> ImageBlock {
+        ImageBlock { path: path.to_string(), alt: String::new(), title: String::new(), width, height, quote_depth: 0 }
+    }
+
+    #[test]
+    fn density_suffix() {
+        assert_eq!(pixel_density("img/logo@2x.png"), 2.0);
+        assert_eq!(pixel_density("https://example.com/a@3x.jpg?raw=true"), 3.0);
+        assert_eq!(pixel_density("me@home.png"), 1.0);
+        assert_eq!(pixel_density("dir@2x/plain.png"), 1.0);
+        assert_eq!(pixel_density("zero@0x.png"), 1.0);
+    }
+
+    #[test]
+    fn image_sizes() {
+        // small images stay small, big ones shrink to fit
+        assert_eq!(image_display_size(&image("icon.png", None, None), (16.0, 16.0), 680.0), (16.0, 16.0));
+        assert_eq!(image_display_size(&image("big.png", None, None), (2000.0, 1000.0), 680.0), (680.0, 340.0));
+        assert_eq!(image_display_size(&image("icon@2x.png", None, None), (64.0, 32.0), 680.0), (32.0, 16.0));
+        // HTML sizes win over the natural size, but still fit the column
+        assert_eq!(image_display_size(&image("a.png", Some(200), None), (400.0, 100.0), 680.0), (200.0, 50.0));
+        assert_eq!(image_display_size(&image("a.png", None, Some(50)), (400.0, 100.0), 680.0), (200.0, 50.0));
+        assert_eq!(image_display_size(&image("a.png", Some(100), Some(100)), (400.0, 100.0), 680.0), (100.0, 100.0));
+        assert_eq!(image_display_size(&image("a.png", Some(1000), None), (400.0, 100.0), 680.0), (680.0, 170.0));
+    }
+}
@@ -0,0 +1,77 @@
+//! Decodes a document's images in the background so its text can be shown
+//! right away. Each image is handed back as soon as it's done.
+
+use images::{self, DecodedImage, ImageError};
+use fetch::Cache;
+use jobs::Jobs;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub struct LoadedImage {
+    pub path: String,
+    pub result: Result<DecodedImage, ImageError>,
+}
+
+pub struct Loader {
+    dir: PathBuf,
+    cache: Arc<Cache>,
+    jobs: Jobs<LoadedImage>,
+}
+
+impl Loader {
+    /// Loads images relative to `dir`, calling `wake` after each one finishes
+    pub fn new(dir: PathBuf, cache: Arc<Cache>, wake: Arc<Fn() + Send + Sync>) -> Loader {
+        Loader { dir, cache, jobs: Jobs::new(wake) }
+    }
+
+    /// Starts loading each path, no wider than the number of pixels it's paired with
+    pub fn load(&mut self, requests: Vec<(String, u32)>) {
+        let (dir, cache) = (self.dir.clone(), self.cache.clone());
+        self.jobs.run(requests, move |(path, max_width)| {
+            let result = images::load(&dir, &path, &cache, max_width);
+            LoadedImage { path, result }
+        });
+    }
+
+    /// Takes the images that finished since the last call, without blocking
+    pub fn poll(&mut self) -> Vec<LoadedImage> {
+        self.jobs.poll()
+    }
+
+    /// Blocks until every requested image is done
+    pub fn wait(&mut self) -> Vec<LoadedImage> {
+        self.jobs.wait()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::mpsc;
+
+    #[test]
+    fn loads_everything_and_wakes() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        let cache = Arc::new(Cache::new(dir.join("no-cache"), true));
+        let (woke, wakes) = mpsc::channel();
+        let woke = Mutex::new(woke);
+        let mut loader = Loader::new(dir, cache, Arc::new(move || woke.lock().unwrap().send(()).unwrap()));
+        loader.load(vec![("rgb.png".to_string(), 100), ("nope.png".to_string(), 100)]);
+        loader.load(vec![("palette.png".to_string(), 1)]);
+
+        let mut loaded = loader.wait();
+        loaded.sort_by(|a, b| a.path.cmp(&b.path));
+        let results: Vec<(&str, bool)> = loaded.iter().map(|image| (&image.path[..], image.result.is_ok())).collect();
+        assert_eq!(results, vec![("nope.png", false), ("palette.png", true), ("rgb.png", true)]);
+        let palette = loaded[1].result.as_ref().unwrap();
+        assert_eq!((palette.width, palette.natural_width), (1, 2));
+        assert!(loader.poll().is_empty());
+        // each wake comes after its image is sent, so they may still be on the way
+        for _ in 0..3 {
+            wakes.recv().unwrap();
+        }
+        assert!(wakes.try_recv().is_err());
+    }
+}
@@ -5,17 +5,44 @@ extern crate app_units;
 extern crate webrender;
 extern crate image;
 extern crate rayon;
-
+extern crate rustybuzz;
+extern crate ureq;
+extern crate dirs;
+extern crate sha2;
+extern crate resvg;
+extern crate syntect;
+extern crate arboard;
+extern crate unicode_segmentation;
+extern crate toml;
+
+mod animation;
 mod app;
+mod args;
+mod budget;
+mod fetch;
+mod headless;
+mod highlight;
+mod images;
+mod jobs;
+mod keys;
+mod shaping;
+mod layout;
+mod loader;
+mod selection;
 mod style;
+mod svg;
 
 use gleam::gl;
 use glutin::GlContext;
 use webrender::api::*;
+use std::process;
 use std::sync::Arc;
 // use webrender::{PROFILER_DBG, RENDER_TARGET_DBG, TEXTURE_CACHE_DBG};
 
 use app::App;
+use args::Args;
+use keys::Action;
+use fetch::Cache;
 
 struct Notifier {
     loop_proxy: Arc<glutin::EventsLoopProxy>,
@@ -44,6 +71,26 @@ impl RenderNotifier for Notifier {
 }
 
 pub fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n{}", err, args::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let cache = Cache::new(Cache::default_dir(), args.offline);
+    if let Some(ref out) = args.render_png {
+        if let Err(err) = headless::render_png(&args.path, out, args.width, cache) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+    run_window(&args.path, cache);
+}
+
+fn run_window(path: &str, cache: Cache) {
     let mut events_loop = glutin::EventsLoop::new();
     let window_builder = glutin::WindowBuilder::new()
         .with_multitouch()
@@ -81,6 +128,7 @@ pub fn main() {
 
     let size = DeviceUintSize::new(width, height);
     let notifier = Box::new(Notifier::new(events_loop.create_proxy()));
+    let image_notifier = notifier.clone();
     let (mut renderer, sender) = webrender::Renderer::new(gl, notifier, opts).unwrap();
     let api = sender.create_api();
     let document_id = api.add_document(size, 0);
@@ -88,14 +136,19 @@ pub fn main() {
     // renderer.set_render_notifier(notifier);
     let pipeline_id = PipelineId(0, 0);
 
-    let args: Vec<String> = std::env::args().collect();
-    let mut app = App::new(&api,pipeline_id, &args[1]);
+    let dpi_scale = gl_window.hidpi_factor();
+    let layout_size = LayoutSize::new((width as f32) / dpi_scale, (height as f32) / dpi_scale);
+    let mut app = match App::new(&api, pipeline_id, path, layout_size, dpi_scale, cache, image_notifier) {
+        Ok(app) => app,
+        Err(err) => {
+            eprintln!("couldn't open {}: {}", path, err);
+            process::exit(1);
+        }
+    };
 
     let epoch = Epoch(0);
     let root_background_color = app.bg_color();
 
-    let dpi_scale = gl_window.hidpi_factor();
-    let layout_size = LayoutSize::new((width as f32) / dpi_scale, (height as f32) / dpi_scale);
     let mut builder = DisplayListBuilder::new(pipeline_id, layout_size);
     let mut resources = ResourceUpdates::new();
 
@@ -118,7 +171,7 @@ pub fn main() {
 
     events_loop.run_forever(|event| {
         // println!("{:?}", event);
-        match event {
+        let needs_render = match event {
             glutin::Event::WindowEvent { event, .. } => {
                 match event {
                     glutin::WindowEvent::Resized(w, h) => {
@@ -128,43 +181,58 @@ pub fn main() {
                         let size = DeviceUintSize::new(width, height);
                         let rect = DeviceUintRect::new(DeviceUintPoint::zero(), size);
                         api.set_window_parameters(document_id, size, rect, gl_window.hidpi_factor());
+                        app.set_device_pixel_ratio(gl_window.hidpi_factor());
                     },
-                    glutin::WindowEvent::Closed |
-                    glutin::WindowEvent::KeyboardInput {
-                        input: glutin::KeyboardInput {virtual_keycode: Some(glutin::VirtualKeyCode::Escape), .. }, ..
-                    } => return glutin::ControlFlow::Break,
+                    glutin::WindowEvent::Closed => return glutin::ControlFlow::Break,
+                    // the app does the rest of the shortcuts in `on_event`
                     glutin::WindowEvent::KeyboardInput {
                         input: glutin::KeyboardInput {
-                            virtual_keycode: Some(glutin::VirtualKeyCode::R),
-                            state: glutin::ElementState::Pressed, ..
+                            virtual_keycode: Some(key),
+                            state: glutin::ElementState::Pressed,
+                            modifiers, ..
                         }, ..
-                    } => {
-                        println!("toggling profiler");
-                        renderer.toggle_debug_flags(webrender::DebugFlags::PROFILER_DBG | webrender::DebugFlags::GPU_TIME_QUERIES);
-                    }
+                    } => match app.key_action(key, modifiers) {
+                        Some(Action::Quit) => return glutin::ControlFlow::Break,
+                        Some(Action::ToggleProfiler) => {
+                            println!("toggling profiler");
+                            renderer.toggle_debug_flags(webrender::DebugFlags::PROFILER_DBG | webrender::DebugFlags::GPU_TIME_QUERIES);
+                        }
+                        _ => (),
+                    },
                     _ => (),
                 }
 
                 let dpi_scale = gl_window.hidpi_factor();
                 let layout_size = LayoutSize::new((width as f32) / dpi_scale, (height as f32) / dpi_scale);
-                if app.on_event(event, &api, layout_size, document_id) {
-                    let mut builder = DisplayListBuilder::new(pipeline_id, layout_size);
-                    let mut resources = ResourceUpdates::new();
-
-                    app.render(&api, &mut builder, &mut resources, layout_size, pipeline_id, document_id);
-                    api.set_display_list(
-                        document_id,
-                        epoch,
-                        Some(root_background_color),
-                        layout_size,
-                        builder.finalize(),
-                        true,
-                        resources
-                    );
-                    api.generate_frame(document_id, None);
-                }
+                app.on_event(event, &api, layout_size, document_id)
             },
-            _ => (),
+            // an image or code block finished loading, or Webrender has a frame ready
+            glutin::Event::Awakened => {
+                let highlighted = app.poll_highlights();
+                app.poll_images(&api) || highlighted
+            }
+            _ => false,
+        };
+        // also starts or pauses animations that scrolled into or out of view
+        let animated = app.animate(&api);
+
+        if needs_render || animated {
+            let dpi_scale = gl_window.hidpi_factor();
+            let layout_size = LayoutSize::new((width as f32) / dpi_scale, (height as f32) / dpi_scale);
+            let mut builder = DisplayListBuilder::new(pipeline_id, layout_size);
+            let mut resources = ResourceUpdates::new();
+
+            app.render(&api, &mut builder, &mut resources, layout_size, pipeline_id, document_id);
+            api.set_display_list(
+                document_id,
+                epoch,
+                Some(root_background_color),
+                layout_size,
+                builder.finalize(),
+                true,
+                resources
+            );
+            api.generate_frame(document_id, None);
         }
 
         renderer.update();
@@ -0,0 +1,321 @@
+//! Selecting text across blocks and copying it back out, as plain text or as
+//! Markdown. Positions are byte offsets into a block's text. Images and
+//! tables can't be selected into, they're a single unit that's either in the
+//! selection or not.
+
+use fasternet_common::*;
+use std::cmp;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A place between two chars of the document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub block: usize,
+    /// Offset into the block's text, or 0 before and 1 after an image or table
+    pub byte: usize,
+}
+
+/// How much a click selects, and what dragging from it selects by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Char,
+    Word,
+    Block,
+}
+
+pub struct Selection {
+    /// Where the selection was started, grown to the unit
+    anchor: Range<Position>,
+    /// Where it's been dragged to, grown to the unit
+    focus: Range<Position>,
+    unit: Unit,
+}
+
+impl Selection {
+    pub fn new(model: &[Block], at: Position, unit: Unit) -> Selection {
+        let anchor = expand(model, at, unit);
+        Selection { focus: anchor.clone(), anchor, unit }
+    }
+
+    /// Moves the dragged end, returning whether that changed what's selected
+    pub fn extend(&mut self, model: &[Block], to: Position) -> bool {
+        let focus = expand(model, to, self.unit);
+        let changed = focus != self.focus;
+        self.focus = focus;
+        changed
+    }
+
+    /// Selections can be dragged backwards, this is always in document order
+    pub fn range(&self) -> Range<Position> {
+        cmp::min(self.anchor.start, self.focus.start)..cmp::max(self.anchor.end, self.focus.end)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let range = self.range();
+        range.start == range.end
+    }
+
+    /// The selected part of block `index`, which is `len` long
+    pub fn block_range(&self, index: usize, len: usize) -> Option<Range<usize>> {
+        part(&self.range(), index, len)
+    }
+}
+
+/// How many positions past the start of a block its end is
+pub fn block_len(block: &Block) -> usize {
+    match *block {
+        Block::Text(ref text) => text.content.len(),
+        Block::Image(_) | Block::Table(_) => 1,
+    }
+}
+
+fn part(range: &Range<Position>, index: usize, len: usize) -> Option<Range<usize>> {
+    if index < range.start.block || index > range.end.block {
+        return None;
+    }
+    let start = if index == range.start.block { range.start.byte } else { 0 };
+    let end = if index == range.end.block { range.end.byte } else { len };
+    if start < end { Some(start..end) } else { None }
+}
+
+/// The selected blocks along with the part of each that's selected
+fn parts(model: &[Block], range: Range<Position>) -> Vec<(&Block, Range<usize>)> {
+    model.iter().enumerate()
+        .filter_map(|(index, block)| part(&range, index, block_len(block)).map(|part| (block, part)))
+        .collect()
+}
+
+fn expand(model: &[Block], at: Position, unit: Unit) -> Range<Position> {
+    let (start, end) = match (model.get(at.block), unit) {
+        (_, Unit::Char) | (None, _) => return at..at,
+        (Some(Block::Text(text)), Unit::Word) => word_bounds(&text.content, at.byte),
+        (Some(block), _) => (0, block_len(block)),
+    };
+    Position { block: at.block, byte: start }..Position { block: at.block, byte: end }
+}
+
+/// The word a byte offset is in, or the run of spaces or punctuation mark if that's what's there
+fn word_bounds(text: &str, byte: usize) -> (usize, usize) {
+    text.split_word_bound_indices()
+        .map(|(start, word)| (start, start + word.len()))
+        .find(|&(_, end)| byte < end)
+        .unwrap_or((text.len(), text.len()))
+}
+
+/// The selected text as it reads, with blocks separated by blank lines.
+/// Images are replaced by their alt text and table cells separated by tabs.
+pub fn plain_text(model: &[Block], range: Range<Position>) -> String {
+    let texts: Vec<String> = parts(model, range).into_iter().map(|(block, part)| match *block {
+        Block::Text(ref text) => inline_text(text, part, false),
+        Block::Image(ref image) => image.alt.clone(),
+        Block::Table(ref table) => table_rows(table).iter().map(|row| {
+            row.iter().map(|cell| inline_text(cell, 0..cell.content.len(), false)).collect::<Vec<_>>().join("\t")
+        }).collect::<Vec<_>>().join("\n"),
+    }).collect();
+    texts.iter().map(|text| text.trim_end_matches('\n')).collect::<Vec<_>>().join("\n\n")
+}
+
+/// The selected text as Markdown. Headings, list markers, quotes and code
+/// fences are kept for blocks selected from their start.
+pub fn markdown(model: &[Block], range: Range<Position>) -> String {
+    let texts: Vec<String> = parts(model, range).into_iter().map(|(block, part)| match *block {
+        Block::Text(ref text) => text_markdown(text, part),
+        Block::Image(ref image) => quoted(&image_markdown(image), image.quote_depth),
+        Block::Table(ref table) => quoted(&table_markdown(table), table.quote_depth),
+    }).collect();
+    texts.join("\n\n")
+}
+
+/// Part of a block's text, with inline images as their alt text or as Markdown
+fn inline_text(text: &TextBlock, part: Range<usize>, markdown: bool) -> String {
+    let mut out = String::new();
+    let mut images = text.images.iter();
+    // a link with formatting inside is several chunks, its text is gathered here until it ends
+    let (mut open_link, mut linked) = (None, String::new());
+    for (range, chunk) in text.chunk_ranges() {
+        let link = chunk.link.filter(|&link| markdown && (link as usize) < text.links.len());
+        let styled = if chunk.kind == TextKind::InlineImage {
+            let mut styled = String::new();
+            for (offset, _) in text.content[range.clone()].char_indices() {
+                match images.next() {
+                    Some(image) if part.contains(&(range.start + offset)) => {
+                        styled.push_str(&if markdown { image_markdown(image) } else { image.alt.clone() });
+                    }
+                    _ => (),
+                }
+            }
+            styled
+        } else {
+            let selected = cmp::max(range.start, part.start)..cmp::min(range.end, part.end);
+            if selected.start >= selected.end {
+                continue;
+            }
+            let selected = &text.content[selected];
+            match chunk.kind {
+                TextKind::ParagraphBold if markdown => format!("**{}**", selected),
+                TextKind::ParagraphItalic if markdown => format!("*{}*", selected),
+                TextKind::ParagraphCode if markdown => format!("`{}`", selected),
+                _ => selected.to_string(),
+            }
+        };
+        if styled.is_empty() {
+            continue;
+        }
+        if link != open_link {
+            close_link(&mut out, text, open_link, &mut linked);
+            open_link = link;
+        }
+        if link.is_some() {
+            linked.push_str(&styled);
+        } else {
+            out.push_str(&styled);
+        }
+    }
+    close_link(&mut out, text, open_link, &mut linked);
+    out
+}
+
+fn close_link(out: &mut String, text: &TextBlock, link: Option<u16>, linked: &mut String) {
+    if let Some(link) = link {
+        out.push_str(&format!("[{}]({})", linked, text.links[link as usize].dest));
+        linked.clear();
+    }
+}
+
+fn text_markdown(text: &TextBlock, part: Range<usize>) -> String {
+    let whole_start = part.start == 0;
+    let inline = inline_text(text, part, text.bg != BlockBackground::Code);
+    let mut lines: Vec<String> = if text.bg == BlockBackground::Code && whole_start {
+        let language = text.language.as_deref().unwrap_or("");
+        let mut lines = vec![format!("```{}", language)];
+        lines.extend(inline.trim_end_matches('\n').split('\n').map(|line| line.to_string()));
+        lines.push("```".to_string());
+        lines
+    } else {
+        inline.split('\n').map(|line| line.to_string()).collect()
+    };
+    if !whole_start {
+        return lines.join("\n");
+    }
+
+    if let Some(level) = text.heading_level {
+        lines[0] = format!("{} {}", "#".repeat(level as usize), lines[0]);
+    }
+    if let Some(marker) = text.list_marker {
+        let marker = match (marker.checked, marker.kind) {
+            (Some(true), _) => "- [x] ".to_string(),
+            (Some(false), _) => "- [ ] ".to_string(),
+            (None, ListKind::Bullet) => "- ".to_string(),
+            (None, ListKind::Ordered) => format!("{}. ", marker.number),
+        };
+        let nesting = "    ".repeat(text.list_level.saturating_sub(1) as usize);
+        let hanging = " ".repeat(marker.len());
+        for (i, line) in lines.iter_mut().enumerate() {
+            *line = format!("{}{}{}", nesting, if i == 0 { &marker } else { &hanging }, line);
+        }
+    }
+    quoted(&lines.join("\n"), text.quote_depth)
+}
+
+fn quoted(markdown: &str, quote_depth: u8) -> String {
+    let quote = "> ".repeat(quote_depth as usize);
+    markdown.split('\n').map(|line| format!("{}{}", quote, line)).collect::<Vec<_>>().join("\n")
+}
+
+fn image_markdown(image: &ImageBlock) -> String {
+    if image.title.is_empty() {
+        format!("![{}]({})", image.alt, image.path)
+    } else {
+        format!("![{}]({} \"{}\")", image.alt, image.path, image.title)
+    }
+}
+
+fn table_rows(table: &TableBlock) -> Vec<&Vec<TextBlock>> {
+    Some(&table.header).into_iter().chain(table.rows.iter()).collect()
+}
+
+fn table_markdown(table: &TableBlock) -> String {
+    let row_markdown = |cells: Vec<String>| format!("| {} |", cells.join(" | "));
+    let mut lines: Vec<String> = table_rows(table).into_iter().map(|row| {
+        row_markdown(row.iter().map(|cell| {
+            inline_text(cell, 0..cell.content.len(), true).replace('|', "\\|")
+        }).collect())
+    }).collect();
+    let rule = row_markdown(table.alignments.iter().map(|alignment| match *alignment {
+        Alignment::None => "---",
+        Alignment::Left => ":--",
+        Alignment::Center => ":-:",
+        Alignment::Right => "--:",
+    }.to_string()).collect());
+    lines.insert(1, rule);
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fasternet_common::markdown::parse_markdown;
+
+    fn at(block: usize, byte: usize) -> Position {
+        Position { block, byte }
+    }
+
+    #[test]
+    fn drags_by_unit_in_either_direction() {
+        let model = parse_markdown("Some words here\n\nNext one");
+        let mut selection = Selection::new(&model, at(0, 6), Unit::Word);
+        assert_eq!(selection.range(), at(0, 5)..at(0, 10));
+        // dragging back keeps the whole word that was clicked
+        assert!(selection.extend(&model, at(0, 1)));
+        assert_eq!(selection.range(), at(0, 0)..at(0, 10));
+        assert!(!selection.extend(&model, at(0, 2)));
+        assert!(selection.extend(&model, at(1, 0)));
+        assert_eq!(selection.range(), at(0, 5)..at(1, 4));
+        assert_eq!(selection.block_range(0, 15), Some(5..15));
+        assert_eq!(selection.block_range(1, 8), Some(0..4));
+        assert_eq!(selection.block_range(2, 8), None);
+
+        let selection = Selection::new(&model, at(1, 3), Unit::Block);
+        assert_eq!(selection.range(), at(1, 0)..at(1, 8));
+        assert!(Selection::new(&model, at(1, 3), Unit::Char).is_empty());
+    }
+
+    #[test]
+    fn plain_text_across_blocks() {
+        let model = parse_markdown("Some **bold** ![logo](logo.png) text\n\n```rust\nfn main() {}\n```\n\n| a | b |\n|---|---|\n| 1 | 2 |\n\nafter");
+        let text = plain_text(&model, at(0, 5)..at(3, 3));
+        assert_eq!(text, "bold logo text\n\nfn main() {}\n\na\tb\n1\t2\n\naft");
+    }
+
+    #[test]
+    fn markdown_keeps_formatting() {
+        let source = "# Title\n\nSome **bold**, *it* and `code` [linked](a.md)\n\n1. first\n\n> ```rust\n> let x;\n> x\n> ```";
+        let model = parse_markdown(source);
+        let end = at(model.len() - 1, block_len(&model[model.len() - 1]));
+        assert_eq!(markdown(&model, at(0, 0)..end), source);
+        // only part of a block is just its inline formatting
+        assert_eq!(markdown(&model, at(1, 2)..at(1, 7)), "me **bo**");
+    }
+
+    #[test]
+    fn formatted_link_is_one_link() {
+        let source = "see [the **docs**](d.md)[*next*](e.md) now";
+        let model = parse_markdown(source);
+        assert_eq!(markdown(&model, at(0, 0)..at(0, block_len(&model[0]))), source);
+        assert_eq!(markdown(&model, at(0, 6)..at(0, 10)), "[e **do**](d.md)");
+    }
+
+    #[test]
+    fn table_markdown_with_alignment() {
+        let model = parse_markdown("| a | b \\| c |\n|:--|--:|\n| **1** | 2 |");
+        assert_eq!(markdown(&model, at(0, 0)..at(0, 1)), "| a | b \\| c |\n| :-- | --: |\n| **1** | 2 |");
+    }
+
+    #[test]
+    fn quoted_image_and_table_markdown() {
+        let source = "> ![logo](logo.png)\n\n> | a | b |\n> | --- | --- |\n> | 1 | 2 |";
+        let model = parse_markdown(source);
+        assert_eq!(markdown(&model, at(0, 0)..at(1, 1)), source);
+    }
+}
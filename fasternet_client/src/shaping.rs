@@ -0,0 +1,139 @@
+//! Turns runs of text into positioned glyphs with kerning, ligatures and
+//! combining marks. Doesn't touch Webrender so it can be tested without a GL context.
+
+use rustybuzz::{self, Face, UnicodeBuffer};
+
+pub struct Font {
+    data: Vec<u8>,
+    index: u32,
+    units_per_em: f32,
+    /// How far the font reaches below the baseline, in font units
+    descender: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShapedGlyph {
+    pub glyph: u32,
+    /// Byte offset into the shaped text of the first char this glyph was made from
+    pub cluster: usize,
+    pub advance: f32,
+    pub x_offset: f32,
+    /// Positive is down, like layout coordinates
+    pub y_offset: f32,
+}
+
+impl Font {
+    /// Returns `None` if the data isn't a font rustybuzz can read
+    pub fn new(data: Vec<u8>, index: u32) -> Option<Font> {
+        let face = Face::from_slice(&data, index)?;
+        let (units_per_em, descender) = (face.units_per_em() as f32, -(face.descender() as f32));
+        Some(Font { data, index, units_per_em, descender })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// How many pixels below the baseline the font reaches at `size` pixels
+    pub fn descent(&self, size: f32) -> f32 {
+        self.descender * size / self.units_per_em
+    }
+
+    /// Shapes a single run of text in this font at `size` pixels
+    pub fn shape(&self, text: &str, size: f32) -> Vec<ShapedGlyph> {
+        // parsing the face only reads the table directory, so this is cheap
+        let face = Face::from_slice(&self.data, self.index).expect("font was checked when loaded");
+        let mut buffer = UnicodeBuffer::new();
+        buffer.push_str(text);
+        buffer.guess_segment_properties();
+        let output = rustybuzz::shape(&face, &[], buffer);
+
+        let scale = size / self.units_per_em;
+        output.glyph_infos().iter().zip(output.glyph_positions()).map(|(info, pos)| {
+            ShapedGlyph {
+                glyph: info.glyph_id,
+                cluster: info.cluster as usize,
+                advance: (pos.x_advance as f32) * scale,
+                x_offset: (pos.x_offset as f32) * scale,
+                y_offset: -(pos.y_offset as f32) * scale,
+            }
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Read;
+    use super::*;
+
+    fn load(res_path: &str) -> Font {
+        let path = format!("{}/res/{}", env!("CARGO_MANIFEST_DIR"), res_path);
+        let mut bytes = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        Font::new(bytes, 0).unwrap()
+    }
+
+    fn mono() -> Font {
+        load("Roboto_Mono/RobotoMono-Regular.ttf")
+    }
+
+    fn sans() -> Font {
+        load("DejaVu_Sans/DejaVuSans.ttf")
+    }
+
+    #[test]
+    fn monospace_advances_are_uniform() {
+        let glyphs = mono().shape("Hello, world", 14.0);
+        assert_eq!(glyphs.len(), 12);
+        let expected = 1229.0 / 2048.0 * 14.0;
+        for (i, glyph) in glyphs.iter().enumerate() {
+            assert_eq!(glyph.cluster, i);
+            assert!((glyph.advance - expected).abs() < 0.01, "{:?}", glyph);
+        }
+    }
+
+    #[test]
+    fn descent_from_font() {
+        // the descenders of both fonts reach less than a third of the size below the baseline
+        for font in &[mono(), sans()] {
+            let descent = font.descent(14.0);
+            assert!(descent > 2.0 && descent < 14.0 * 0.3, "{}", descent);
+            assert_eq!(font.descent(28.0), descent * 2.0);
+        }
+    }
+
+    #[test]
+    fn kerning() {
+        let font = sans();
+        let alone = font.shape("A", 14.0)[0].advance;
+        let kerned = font.shape("AV", 14.0)[0].advance;
+        assert!(kerned < alone, "{} should be less than {}", kerned, alone);
+    }
+
+    #[test]
+    fn ligature() {
+        let glyphs = sans().shape("file", 14.0);
+        let clusters: Vec<usize> = glyphs.iter().map(|glyph| glyph.cluster).collect();
+        assert_eq!(clusters, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn combining_marks() {
+        // composes into a precomposed glyph when the font has one
+        let composed = sans().shape("e\u{301}", 14.0);
+        assert_eq!(composed.len(), 1);
+        assert_eq!(composed[0].glyph, sans().shape("\u{e9}", 14.0)[0].glyph);
+
+        // otherwise the mark is positioned over its base without advancing
+        let glyphs = sans().shape("q\u{301}x", 14.0);
+        assert_eq!(glyphs.len(), 3);
+        assert_eq!(glyphs[1].cluster, 0);
+        assert_eq!(glyphs[1].advance, 0.0);
+        assert_eq!(glyphs[2].cluster, 3);
+    }
+}
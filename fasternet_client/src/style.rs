@@ -1,13 +1,16 @@
 use webrender::api::*;
 use fasternet_common::*;
 use std::collections::HashMap;
+use std::ops::Range;
+use std::mem;
+use std::rc::Rc;
 use app_units::Au;
-use image::{self, GenericImage};
+use shaping::{Font, ShapedGlyph};
+use layout::{self, FontMetrics, CodeStyle, CodeWrap, TextLayout, TableLayout, Run, Atom, QUOTE_INDENT, CELL_PADDING};
+use images::{DecodedImage, ImageError, PixelFormat};
 
 use std::fs::File;
-use std::u32;
-use std::ops::Range;
-use std::path::{PathBuf, Path};
+use std::path::PathBuf;
 use std::io::{self, Read};
 
 #[derive(Debug, Clone)]
@@ -19,11 +22,36 @@ pub struct ChunkStyle {
     bg_color: Option<ColorF>,
 }
 
+impl ChunkStyle {
+    fn zoomed(&self, zoom: f32) -> ChunkStyle {
+        ChunkStyle {
+            size: Au::from_f32_px(self.size.to_f32_px() * zoom),
+            line_height: Au::from_f32_px(self.line_height.to_f32_px() * zoom),
+            ..self.clone()
+        }
+    }
+}
+
 pub struct BuiltChunkStyle {
     style: ChunkStyle,
+    font: Rc<Font>,
     font_key: FontKey,
     font_instance: FontInstanceKey,
-    char_width: f32,
+}
+
+/// Why part of some text is highlighted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Selection,
+    SearchMatch,
+    /// The search match that was moved to last
+    ActiveMatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageAlign {
+    Left,
+    Center,
 }
 
 pub struct Theme {
@@ -31,46 +59,130 @@ pub struct Theme {
     fonts: Vec<&'static str>,
     style_map: HashMap<TextKind, ChunkStyle>,
     code_bg: ColorF,
+    table_border: ColorF,
+    table_header_bg: ColorF,
+    quote_rule: ColorF,
+    /// Laid over selected text, so it needs to be see-through, like the search match colors
+    selection: ColorF,
+    search_match: ColorF,
+    active_match: ColorF,
+    /// Where images narrower than the column go
+    image_align: ImageAlign,
+    /// How code blocks lay out tabs and lines too long for the column
+    pub code_style: CodeStyle,
 }
 
 pub struct BuiltTheme {
     pub bg_color: ColorF,
     code_bg: ColorF,
+    table_border: ColorF,
+    table_header_bg: ColorF,
+    quote_rule: ColorF,
+    selection: ColorF,
+    search_match: ColorF,
+    active_match: ColorF,
+    image_align: ImageAlign,
+    code_style: CodeStyle,
     // fonts: Vec<FontKey>,
     style_map: HashMap<TextKind, BuiltChunkStyle>,
+    /// How much the text is scaled, block images are scaled along with it
+    zoom: f32,
 }
 
-pub struct BuiltTextBlock {
-    glyphs: Vec<u32>,
-    // advances: Vec<f32>,
-    chunks: Vec<BuiltChunk>,
-    pub size: LayoutSize,
-    bg_color: Option<ColorF>,
-}
+/// Webrender keys of the loaded images in a document, by path
+pub type ImageKeys = HashMap<String, ImageKey>;
 
 pub struct BuiltImageBlock {
-    pub key: ImageKey,
-    pub dimensions: LayoutSize,
+    pub source: ImageBlock,
+    /// None until the image is loaded
+    pub key: Option<ImageKey>,
+    /// Natural size in pixels, None until the image's header or the whole image has been read
+    pub dimensions: Option<LayoutSize>,
+    /// The title, centered under the image
+    caption: Option<TextLayout>,
+    /// Drawn instead of the image when it couldn't be loaded
+    pub placeholder: Option<TextLayout>,
+    /// Why the image couldn't be loaded, kept to lay the placeholder out again
+    failure: Option<String>,
+    /// The theme's zoom when it was last laid out
+    zoom: f32,
 }
 
 pub enum BuiltBlock {
-    Text(BuiltTextBlock),
+    Text(TextLayout),
     Image(BuiltImageBlock),
+    Table(TableLayout),
 }
 
-#[derive(Debug)]
-pub struct BuiltChunk {
-    range: Range<usize>,
-    char_width: f32,
-    height: f32,
-    font_instance: FontInstanceKey,
-    color: ColorF,
-    newline: bool,
-    bg_color: Option<ColorF>,
+impl BuiltBlock {
+    pub fn height(&self, width: f32) -> f32 {
+        match *self {
+            BuiltBlock::Text(ref text) => text.height,
+            BuiltBlock::Image(ref image_block) => image_block.height(width),
+            BuiltBlock::Table(ref table) => table.height,
+        }
+    }
+
+    pub fn in_list(&self) -> bool {
+        match *self {
+            BuiltBlock::Text(ref text) => text.in_list(),
+            _ => false,
+        }
+    }
+
+    /// Space left of an image or table for the blockquotes it's in
+    pub fn indent(&self) -> f32 {
+        match *self {
+            BuiltBlock::Text(_) => 0.0,
+            BuiltBlock::Image(ref image_block) => image_block.indent(),
+            BuiltBlock::Table(ref table) => table.indent,
+        }
+    }
+
+    pub fn link_at(&self, origin: LayoutPoint, point: LayoutPoint) -> Option<&Link> {
+        let (x, y) = (point.x - origin.x, point.y - origin.y);
+        match *self {
+            BuiltBlock::Text(ref text) => text.link_at(x, y),
+            BuiltBlock::Image(_) => None,
+            BuiltBlock::Table(ref table) => table.link_at(x, y),
+        }
+    }
 }
 
 impl Theme {
     pub fn new() -> Self {
+        Self::with_fonts(vec![
+            "Roboto_Mono/RobotoMono-Regular.ttf",
+            "Roboto_Mono/RobotoMono-Bold.ttf",
+            "Roboto_Mono/RobotoMono-Italic.ttf",
+        ])
+    }
+
+    /// Sets prose in DejaVu Sans, code is still monospace. Images are left
+    /// aligned like they are on GitHub, and long lines of code wrap rather
+    /// than scroll to keep with the flowing text.
+    pub fn proportional() -> Self {
+        let mut theme = Self::with_fonts(vec![
+            "DejaVu_Sans/DejaVuSans.ttf",
+            "DejaVu_Sans/DejaVuSans-Bold.ttf",
+            "DejaVu_Sans/DejaVuSans-Oblique.ttf",
+        ]);
+        theme.image_align = ImageAlign::Left;
+        theme.code_style.wrap = CodeWrap::Wrap;
+        theme
+    }
+
+    /// Takes the regular, bold and italic fonts used for prose
+    fn with_fonts(mut fonts: Vec<&'static str>) -> Self {
+        const MONO: &str = "Roboto_Mono/RobotoMono-Regular.ttf";
+        let code_font = match fonts.iter().position(|&font| font == MONO) {
+            Some(index) => index,
+            None => {
+                fonts.push(MONO);
+                fonts.len() - 1
+            }
+        };
+
         let mut style_map = HashMap::new();
         style_map.insert(TextKind::Paragraph, ChunkStyle {
             color: ColorF::new(0.39607, 0.48235, 0.5137, 1.0),
@@ -83,9 +195,30 @@ impl Theme {
             color: ColorF::new(0.39607, 0.48235, 0.5137, 1.0),
             size: Au::from_px(14),
             line_height: Au::from_px(16),
-            font: 0,
+            font: code_font,
             bg_color: Some(ColorF::new(0.9333, 0.9098, 0.8352, 1.0)),
         });
+        style_map.insert(TextKind::CodeBlock, ChunkStyle {
+            color: ColorF::new(0.39607, 0.48235, 0.5137, 1.0),
+            size: Au::from_px(14),
+            line_height: Au::from_px(16),
+            font: code_font,
+            bg_color: None,
+        });
+        // highlighted code uses the accent colors of the Solarized palette the rest comes from
+        let code_colors = [
+            (TextKind::CodeComment, ColorU::new(147, 161, 161, 255)),
+            (TextKind::CodeKeyword, ColorU::new(133, 153, 0, 255)),
+            (TextKind::CodeString, ColorU::new( 42, 161, 152, 255)),
+            (TextKind::CodeConstant, ColorU::new(211, 54, 130, 255)),
+            (TextKind::CodeType, ColorU::new(181, 137, 0, 255)),
+            (TextKind::CodeFunction, ColorU::new( 38, 139, 210, 255)),
+        ];
+        for &(kind, color) in &code_colors {
+            let mut style = style_map[&TextKind::CodeBlock].clone();
+            style.color = ColorF::from(color);
+            style_map.insert(kind, style);
+        }
         style_map.insert(TextKind::ParagraphBold, ChunkStyle {
             color: ColorF::new(0.39607, 0.48235, 0.5137, 1.0),
             size: Au::from_px(14),
@@ -107,6 +240,21 @@ impl Theme {
             font: 0,
             bg_color: None,
         });
+        // only sizes the line an inline image sits on, no glyphs are drawn
+        style_map.insert(TextKind::InlineImage, ChunkStyle {
+            color: ColorF::new(0.39607, 0.48235, 0.5137, 1.0),
+            size: Au::from_px(14),
+            line_height: Au::from_px(16),
+            font: 0,
+            bg_color: None,
+        });
+        style_map.insert(TextKind::Caption, ChunkStyle {
+            color: ColorF::from(ColorU::new(147, 161, 161, 255)),
+            size: Au::from_px(13),
+            line_height: Au::from_px(15),
+            font: 2,
+            bg_color: None,
+        });
         style_map.insert(TextKind::Header1, ChunkStyle {
             color: ColorF::from(ColorU::new( 88, 110, 117, 255)),
             size: Au::from_px(25),
@@ -121,16 +269,46 @@ impl Theme {
             font: 1,
             bg_color: None,
         });
+        style_map.insert(TextKind::Header3, ChunkStyle {
+            color: ColorF::from(ColorU::new( 88, 110, 117, 255)),
+            size: Au::from_px(16),
+            line_height: Au::from_px(18),
+            font: 1,
+            bg_color: None,
+        });
+        style_map.insert(TextKind::Header4, ChunkStyle {
+            color: ColorF::from(ColorU::new( 88, 110, 117, 255)),
+            size: Au::from_px(15),
+            line_height: Au::from_px(17),
+            font: 1,
+            bg_color: None,
+        });
+        style_map.insert(TextKind::Header5, ChunkStyle {
+            color: ColorF::from(ColorU::new( 88, 110, 117, 255)),
+            size: Au::from_px(14),
+            line_height: Au::from_px(16),
+            font: 1,
+            bg_color: None,
+        });
+        style_map.insert(TextKind::Header6, ChunkStyle {
+            color: ColorF::from(ColorU::new(147, 161, 161, 255)),
+            size: Au::from_px(14),
+            line_height: Au::from_px(16),
+            font: 1,
+            bg_color: None,
+        });
         Theme {
             bg_color: ColorF::from(ColorU::new(253, 246, 227, 255)),
             code_bg:  ColorF::new(0.9333, 0.9098, 0.8352, 1.0),
-            fonts: vec![
-                "Roboto_Mono/RobotoMono-Regular.ttf",
-                "Roboto_Mono/RobotoMono-Bold.ttf",
-                "Roboto_Mono/RobotoMono-Italic.ttf",
-                // "Roboto/Roboto-Regular.ttf",
-                // "Open_Sans/OpenSans-Regular.ttf",
-            ],
+            table_border: ColorF::from(ColorU::new(147, 161, 161, 255)),
+            table_header_bg: ColorF::new(0.9333, 0.9098, 0.8352, 1.0),
+            quote_rule: ColorF::from(ColorU::new(147, 161, 161, 255)),
+            selection: ColorF::from(ColorU::new( 38, 139, 210, 64)),
+            search_match: ColorF::from(ColorU::new(181, 137, 0, 64)),
+            active_match: ColorF::from(ColorU::new(203, 75, 22, 112)),
+            image_align: ImageAlign::Center,
+            code_style: CodeStyle::default(),
+            fonts,
             style_map,
         }
     }
@@ -139,28 +317,49 @@ impl Theme {
 impl BuiltTheme {
     pub fn new(theme: &Theme, api: &RenderApi) -> BuiltTheme {
         // TODO combine all of these into one resource update
-        let fonts: Vec<FontKey> = theme.fonts.iter().map(|res_path| {
+        let fonts: Vec<(FontKey, Rc<Font>)> = theme.fonts.iter().map(|res_path| {
             let bytes = Self::read_resource(res_path).unwrap();
-            Self::load_font(api, bytes, 0) // TODO understand index
+            let font = Font::new(bytes, 0).expect("couldn't parse font"); // TODO understand index
+            (Self::load_font(api, font.data().to_vec(), font.index()), Rc::new(font))
         }).collect();
 
         let style_map = theme.style_map.iter().map(|(k, style)| {
-            let font_key: FontKey = fonts[style.font];
+            let (font_key, ref font) = fonts[style.font];
             // TODO don't create redundant instances
             let font_instance = Self::add_font_instance(api, font_key, style.size);
-            let char_width = Self::find_char_width(api, font_key, font_instance);
-            let built = BuiltChunkStyle { style: style.clone(), font_instance, font_key, char_width };
+            let built = BuiltChunkStyle { style: style.clone(), font: font.clone(), font_key, font_instance };
             (k.clone(), built)
         }).collect();
 
         BuiltTheme {
             bg_color: theme.bg_color,
             code_bg: theme.code_bg,
+            table_border: theme.table_border,
+            table_header_bg: theme.table_header_bg,
+            quote_rule: theme.quote_rule,
+            selection: theme.selection,
+            search_match: theme.search_match,
+            active_match: theme.active_match,
+            image_align: theme.image_align,
+            code_style: theme.code_style,
             // fonts,
             style_map,
+            zoom: 1.0,
         }
     }
 
+    /// Scales the text of `theme`, the one this was built from, by `zoom`.
+    /// Returns the font instances for the old sizes, they have to be kept
+    /// until the display list that still uses them is replaced.
+    pub fn set_zoom(&mut self, theme: &Theme, zoom: f32, api: &RenderApi) -> Vec<FontInstanceKey> {
+        self.zoom = zoom;
+        self.style_map.iter_mut().map(|(kind, built)| {
+            built.style = theme.style_map[kind].zoomed(zoom);
+            let font_instance = Self::add_font_instance(api, built.font_key, built.style.size);
+            mem::replace(&mut built.font_instance, font_instance)
+        }).collect()
+    }
+
     fn read_resource(res_path: &str) -> io::Result<Vec<u8>> {
         let mut path = PathBuf::new();
         path.push("fasternet_client/res");
@@ -192,229 +391,263 @@ impl BuiltTheme {
         api.update_resources(update);
         key
     }
+}
 
-    fn find_char_width(api: &RenderApi, font_key: FontKey, instance: FontInstanceKey) -> f32 {
-        let index: u32 = api.get_glyph_indices(font_key, "m")[0].unwrap();
-        let mut keys = Vec::new();
-        keys.push(GlyphKey::new(index,
-                                LayerPoint::zero(),
-                                FontRenderMode::Subpixel,
-                                SubpixelDirection::Horizontal));
-        let metrics = api.get_glyph_dimensions(instance, keys);
-        metrics[0].unwrap().advance
+const QUOTE_RULE_WIDTH: f32 = 3.0;
+const SEARCH_BAR_WIDTH: f32 = 360.0;
+const SEARCH_BAR_PADDING: f32 = 8.0;
+const SEARCH_BAR_MARGIN: f32 = 10.0;
+/// Drawn at the end of lines of code that were too long and wrapped
+const CONTINUATION_MARKER: &str = "\u{bb}";
+
+/// The background of a code block, with a little room around its text
+fn code_rect(text: &TextLayout, origin: LayoutPoint) -> LayoutRect {
+    let size = LayoutSize::new(text.width - text.indent, text.height);
+    LayoutRect::new(origin + LayoutVector2D::new(text.indent, 0.0), size).inflate(3.0, 3.0)
+}
+
+impl FontMetrics for BuiltTheme {
+    fn shape(&self, kind: TextKind, text: &str) -> Vec<ShapedGlyph> {
+        let style = &self.style_map[&kind];
+        style.font.shape(text, style.style.size.to_f32_px())
+    }
+
+    fn font_size(&self, kind: TextKind) -> f32 {
+        self.style_map[&kind].style.size.to_f32_px()
+    }
+
+    fn line_height(&self, kind: TextKind) -> f32 {
+        self.style_map[&kind].style.line_height.to_f32_px()
+    }
+
+    fn descent(&self, kind: TextKind) -> f32 {
+        let style = &self.style_map[&kind];
+        style.font.descent(style.style.size.to_f32_px())
+    }
+
+    fn code_style(&self) -> CodeStyle {
+        self.code_style
     }
 }
 
-#[derive(Debug)]
-enum Token {
-    Word(usize),
-    Space,
-    Newline,
-    End,
+/// Measures text with the theme and inline images by their decoded size
+pub struct DocumentMetrics<'a> {
+    pub theme: &'a BuiltTheme,
+    pub image_sizes: &'a HashMap<String, (f32, f32)>,
 }
 
-impl BuiltTextBlock {
-    pub fn new(block: &TextBlock, theme: &BuiltTheme, api: &RenderApi, width: f32) -> Self {
-        let mut indices = Vec::with_capacity(block.content.len());
-        // let mut advances = Vec::with_capacity(block.content.len());
-        let mut chunks = Vec::with_capacity(block.chunks.len());
-
-        let mut x = 0.0;
-        let mut height = 0.0;
-        let mut first_chunk = true;
-        for chunk in &block.chunks {
-            let range = (chunk.start as usize)..(chunk.end as usize);
-            let chunk_str = &block.content[range.clone()];
-            let style = &theme.style_map[&chunk.kind];
-
-            // even if this gets split, the whole thing is still the same font
-            Self::layout_glyphs(api, style.font_key, chunk_str,
-                &mut indices);
-
-            Self::build_chunks(
-                &mut chunks, &mut height, &mut x,
-                range, &chunk_str, &style,
-                first_chunk, width,
-            );
-            // TODO incorrect fudge for descenders
-            if first_chunk {
-                height += style.style.size.to_f32_px() * 0.3;
-            }
-            first_chunk = false;
+impl<'a> FontMetrics for DocumentMetrics<'a> {
+    fn shape(&self, kind: TextKind, text: &str) -> Vec<ShapedGlyph> {
+        self.theme.shape(kind, text)
+    }
+
+    fn font_size(&self, kind: TextKind) -> f32 {
+        self.theme.font_size(kind)
+    }
+
+    fn line_height(&self, kind: TextKind) -> f32 {
+        self.theme.line_height(kind)
+    }
+
+    fn descent(&self, kind: TextKind) -> f32 {
+        self.theme.descent(kind)
+    }
+
+    fn image_size(&self, path: &str) -> Option<(f32, f32)> {
+        self.image_sizes.get(path).cloned()
+    }
+
+    fn code_style(&self) -> CodeStyle {
+        self.theme.code_style()
+    }
+}
+
+impl BuiltTheme {
+    pub fn draw_text(&self, builder: &mut DisplayListBuilder, text: &TextLayout, images: &ImageKeys, origin: LayoutPoint) {
+        self.draw_decorations(builder, text, images, origin);
+        self.draw_contents(builder, text, images, origin);
+    }
+
+    /// Draws a text block of the document with parts of its text highlighted.
+    /// A code block too wide for the column gets its text drawn in a scroll
+    /// frame of its own, `scroll_frame`, so it can be scrolled sideways.
+    pub fn draw_document_text(&self, builder: &mut DisplayListBuilder, text: &TextLayout, images: &ImageKeys,
+                              origin: LayoutPoint, highlights: &[(Range<usize>, Highlight)], scroll_frame: Option<ClipId>) {
+        self.draw_decorations(builder, text, images, origin);
+        if let Some(scroll_frame) = scroll_frame {
+            let rect = code_rect(text, origin);
+            let content_rect = LayoutRect::new(rect.origin, LayoutSize::new(rect.size.width + text.scroll_width(), rect.size.height));
+            let clip = ComplexClipRegion {
+                rect, radii: BorderRadius::uniform(5.0),
+                mode: ClipMode::Clip,
+            };
+            builder.define_scroll_frame(
+                Some(scroll_frame),
+                content_rect,
+                rect,
+                vec![clip],
+                None,
+                ScrollSensitivity::ScriptAndInputEvents);
+            builder.push_clip_id(scroll_frame);
+        }
+        self.draw_contents(builder, text, images, origin);
+        self.draw_highlights(builder, text, origin, highlights);
+        if scroll_frame.is_some() {
+            builder.pop_clip_id();
         }
-        // println!("{:?} - {}", block.content, block.content.len());
-        // println!("{:?}", chunks);
+    }
 
-        let size = LayoutSize::new(width, height);
-        let bg_color = match block.bg {
-            BlockBackground::NoBackground => None,
-            BlockBackground::Code => Some(theme.code_bg),
-        };
-        BuiltTextBlock { glyphs: indices, chunks, size, bg_color }
-    }
-
-    fn build_chunks(chunks: &mut Vec<BuiltChunk>, total_height: &mut f32, x: &mut f32, range: Range<usize>,
-                    chunk_str: &str, style: &BuiltChunkStyle,  first_chunk: bool, width: f32) {
-        let mut flush_line = |range: &mut Range<usize>, newline: bool| {
-            if range.start != range.end {
-                // println!("chunk {:?} {:?} {:?}", total_height, range, newline);
-                let height = style.style.line_height.to_f32_px();
-                chunks.push(BuiltChunk {
-                    char_width: style.char_width,
-                    font_instance: style.font_instance,
-                    color: style.style.color,
-                    bg_color: style.style.bg_color,
-                    range: range.clone(), newline, height,
-                });
-                if newline {
-                    *total_height = *total_height + height;
-                }
-                range.start = range.end;
+    /// Highlights ranges of the text of a block that's already drawn
+    pub fn draw_highlights(&self, builder: &mut DisplayListBuilder, text: &TextLayout, origin: LayoutPoint,
+                           highlights: &[(Range<usize>, Highlight)]) {
+        for &(ref range, highlight) in highlights {
+            for (x, y, width, height) in text.range_rects(range.clone()) {
+                let rect = LayoutRect::new(origin + LayoutVector2D::new(x, y), LayoutSize::new(width, height));
+                self.draw_highlight(builder, rect, highlight);
             }
+        }
+    }
+
+    /// Highlights something by drawing over it
+    pub fn draw_highlight(&self, builder: &mut DisplayListBuilder, rect: LayoutRect, highlight: Highlight) {
+        let color = match highlight {
+            Highlight::Selection => self.selection,
+            Highlight::SearchMatch => self.search_match,
+            Highlight::ActiveMatch => self.active_match,
         };
+        builder.push_rect(&PrimitiveInfo::new(rect), color);
+    }
 
-        let mut cur_chunk = range.start..range.start;
-        let tokens = Self::tokenize(chunk_str);
-        // println!("{:?}", tokens);
-        let mut newline = first_chunk;
-        for token in tokens.into_iter() {
-            match token {
-                Token::Word(word_len) => {
-                    let space_left = width - *x;
-                    let chars_left = (space_left / style.char_width) as usize;
-                    let doesnt_fit = (space_left < 0.0) || (word_len > chars_left);
-                    // println!("s_left={} c_left={} word={} - {:?} {:?}", space_left, chars_left, word_len, &chunk_str[(cur_chunk.start-range.start)..(cur_chunk.end-range.start)], &chunk_str[(cur_chunk.end-range.start)..(cur_chunk.end+word_len-range.start)]);
-                    if doesnt_fit {
-                        flush_line(&mut cur_chunk, newline); *x = 0.0; newline = true;
-                    }
-
-                    cur_chunk.end += word_len;
-                    *x += (word_len as f32) * style.char_width;
-                },
-                Token::Space => {
-                    cur_chunk.end += 1;
-                    *x += style.char_width;
-                }
-                Token::Newline => {
-                    cur_chunk.end += 1;
-                    flush_line(&mut cur_chunk, newline); *x = 0.0; newline = true;
-                }
-                Token::End => {
-                    flush_line(&mut cur_chunk, newline);
-                }
-            }
+    /// Draws the search bar over the top right corner of the window, with the
+    /// query on the left, how it's going on the right and a caret after the
+    /// query while it's being typed
+    pub fn draw_search_bar(&self, builder: &mut DisplayListBuilder, query: &TextLayout, status: &TextLayout,
+                           caret: bool, viewport_width: f32) {
+        let width = SEARCH_BAR_WIDTH.min(viewport_width - SEARCH_BAR_MARGIN * 2.0);
+        let height = query.height.max(status.height) + SEARCH_BAR_PADDING * 2.0;
+        let origin = LayoutPoint::new(viewport_width - width - SEARCH_BAR_MARGIN, SEARCH_BAR_MARGIN);
+        self.draw_box(builder, LayoutRect::new(origin, LayoutSize::new(width, height)));
+
+        let images = ImageKeys::new();
+        let text_origin = origin + LayoutVector2D::new(SEARCH_BAR_PADDING, SEARCH_BAR_PADDING);
+        self.draw_text(builder, query, &images, text_origin);
+        if caret {
+            let caret = LayoutRect::new(text_origin + LayoutVector2D::new(query.content_width + 1.0, 0.0),
+                                        LayoutSize::new(1.5, query.height));
+            builder.push_rect(&PrimitiveInfo::new(caret), self.table_border);
         }
+        let status_x = width - SEARCH_BAR_PADDING - status.content_width;
+        self.draw_text(builder, status, &images, origin + LayoutVector2D::new(status_x, SEARCH_BAR_PADDING));
     }
 
-    fn tokenize(chunk_str: &str) -> Vec<Token> {
-        let mut tokens = vec![];
-        let mut byte_iter = chunk_str.chars();
-        'outer: loop {
-            // read a single word
-            let mut word_len = 0;
-            loop {
-                match byte_iter.next() {
-                    Some(' ') => {
-                        if word_len > 0 { tokens.push(Token::Word(word_len)) }
-                        tokens.push(Token::Space);
-                        break;
-                    },
-                    Some('\n') => {
-                        if word_len > 0 { tokens.push(Token::Word(word_len)) }
-                        tokens.push(Token::Newline);
-                        break;
-                    }
-                    Some(_) => word_len += 1,
-                    None => {
-                        if word_len > 0 { tokens.push(Token::Word(word_len)) }
-                        break 'outer;
-                    }
-                }
-            }
+    /// How far down the window the search bar reaches, text above it is hidden
+    pub fn search_bar_bottom(&self) -> f32 {
+        SEARCH_BAR_MARGIN + SEARCH_BAR_PADDING * 2.0 + self.line_height(TextKind::Paragraph) * 1.3
+    }
+
+    fn draw_quote_rules(&self, builder: &mut DisplayListBuilder, quote_depth: u8, origin: LayoutPoint, height: f32) {
+        for level in 0..quote_depth {
+            let x = origin.x + (level as f32) * QUOTE_INDENT;
+            let rect = LayoutRect::new(LayoutPoint::new(x, origin.y - 3.0),
+                                       LayoutSize::new(QUOTE_RULE_WIDTH, height + 6.0));
+            builder.push_rect(&PrimitiveInfo::new(rect), self.quote_rule);
+        }
+    }
+
+    /// Everything that doesn't scroll with the text of a code block
+    fn draw_decorations(&self, builder: &mut DisplayListBuilder, text: &TextLayout, images: &ImageKeys, origin: LayoutPoint) {
+        self.draw_quote_rules(builder, text.quote_depth, origin, text.height);
+
+        if let Some((ref marker, left)) = text.marker {
+            self.draw_text(builder, marker, images, origin + LayoutVector2D::new(left, 0.0));
         }
-        tokens.push(Token::End);
-        tokens
-    }
-
-    fn layout_glyphs(api: &RenderApi, font_key: FontKey, text: &str,
-                        indices_out: &mut Vec<u32>) {
-                        // indices_out: &mut Vec<u32>, advances_out: &mut Vec<f32>) {
-        // let indices_iter = api.get_glyph_indices(font_key, text).iter()
-        //                            .map(|idx| idx.unwrap_or(u32::max_value()));
-        let indices: Vec<u32> = api
-                            .get_glyph_indices(font_key, text)
-                            .iter()
-                            .map(|idx| idx.unwrap_or(u32::max_value()))
-                            .collect();
-
-        // let font = FontInstance::new(font_key,
-        //                              size,
-        //                              ColorF::new(0.0, 0.0, 0.0, 1.0),
-        //                              FontRenderMode::Subpixel,
-        //                              SubpixelDirection::Horizontal,
-        //                              None);
-        // let mut keys = Vec::new();
-        // for glyph_index in &indices {
-        //     keys.push(GlyphKey::new(*glyph_index,
-        //                             LayerPoint::zero(),
-        //                             FontRenderMode::Subpixel,
-        //                             SubpixelDirection::Horizontal));
-        // }
-        // let metrics = api.get_glyph_dimensions(font, keys);
-
-        indices_out.extend(indices.into_iter());
-
-        // let space_advance = size.to_f32_px() / 3.0;
-        // let advances_iter = metrics.iter()
-        //                            .map(|m| m.map(|dim| dim.advance).unwrap_or(space_advance));
-        // advances_out.extend(advances_iter);
-    }
-
-    pub fn draw(&self, builder: &mut DisplayListBuilder, origin: LayoutPoint) {
-        if let Some(color) = self.bg_color {
-            let rect = LayoutRect::new(origin, self.size);
-            let rect = rect.inflate(3.0,3.0);
+
+        if text.bg == BlockBackground::Code {
+            let rect = code_rect(text, origin);
             let clip = ComplexClipRegion {
                 rect, radii: BorderRadius::uniform(5.0),
                 mode: ClipMode::Clip,
             };
             let info = LayoutPrimitiveInfo::with_clip(rect, LocalClip::RoundedRect(rect, clip));
             // let rect = rect.scale(1.1,1.0);
-            builder.push_rect(&info, color);
+            builder.push_rect(&info, self.code_bg);
+        }
+    }
+
+    fn draw_contents(&self, builder: &mut DisplayListBuilder, text: &TextLayout, images: &ImageKeys, origin: LayoutPoint) {
+        for run in &text.runs {
+            self.draw_run(builder, text, run, origin);
         }
 
-        let mut pt = origin;
-        for chunk in &self.chunks {
-            pt = self.draw_chunk(builder, pt, chunk, origin.x);
+        for atom in &text.atoms {
+            self.draw_atom(builder, atom, images, origin);
+        }
+
+        for &(x, baseline) in &text.continuations {
+            self.draw_continuation(builder, origin + LayoutVector2D::new(x, baseline));
         }
     }
 
-    fn draw_chunk(&self, builder: &mut DisplayListBuilder, mut pt: LayoutPoint, chunk: &BuiltChunk, left: f32) -> LayoutPoint {
-        let glyphs = &self.glyphs[chunk.range.clone()];
+    /// Draws the marker of a wrapped line of code, `point` is its left end on the baseline
+    fn draw_continuation(&self, builder: &mut DisplayListBuilder, point: LayoutPoint) {
+        let style = &self.style_map[&TextKind::CodeComment];
+        let mut pt = point;
+        let glyphs = self.shape(TextKind::CodeComment, CONTINUATION_MARKER).iter().map(|glyph| {
+            let gi = GlyphInstance { index: glyph.glyph,
+                                     point: pt + LayoutVector2D::new(glyph.x_offset, glyph.y_offset), };
+            pt.x += glyph.advance;
+            gi
+        }).collect::<Vec<_>>();
+        let line_height = style.style.line_height.to_f32_px();
+        let rect = LayoutRect::new(point - LayoutVector2D::new(0.0, line_height),
+                                   LayoutSize::new(pt.x - point.x, line_height + self.descent(TextKind::CodeComment)));
+        let options = GlyphOptions {
+            render_mode: FontRenderMode::Subpixel,
+        };
+        builder.push_text(&LayoutPrimitiveInfo::new(rect),
+             &glyphs,
+             style.font_instance,
+             style.style.color,
+             Some(options));
+    }
 
-        if chunk.newline {
-            pt.y += chunk.height;
-            pt.x = left;
+    fn draw_atom(&self, builder: &mut DisplayListBuilder, atom: &Atom, images: &ImageKeys, origin: LayoutPoint) {
+        let size = LayoutSize::new(atom.width, atom.height);
+        let rect = LayoutRect::new(origin + LayoutVector2D::new(atom.x, atom.y), size);
+        match images.get(&atom.path) {
+            Some(&key) if atom.sized => {
+                builder.push_image(&PrimitiveInfo::new(rect), size, LayoutSize::zero(), ImageRendering::Auto, key);
+            }
+            _ => self.draw_box(builder, rect),
         }
-        let text_start_x = pt.x;
+    }
+
+    /// The outlined box shown where an image isn't loaded (yet)
+    fn draw_box(&self, builder: &mut DisplayListBuilder, rect: LayoutRect) {
+        builder.push_rect(&PrimitiveInfo::new(rect), self.table_border);
+        builder.push_rect(&PrimitiveInfo::new(rect.inflate(-1.0, -1.0)), self.code_bg);
+    }
 
-        // let advances = &self.advances[chunk.range.clone()];
-        // let glyphs = glyphs.iter().zip(advances).map(|arg| {
-        let glyphs = glyphs.iter().map(|glyph| {
+    fn draw_run(&self, builder: &mut DisplayListBuilder, text: &TextLayout, run: &Run, origin: LayoutPoint) {
+        let style = &self.style_map[&run.kind];
+        let rect = LayoutRect::new(origin + LayoutVector2D::new(run.x, run.top()),
+                                   LayoutSize::new(run.width, run.bottom() - run.top()));
+        let mut pt = origin + LayoutVector2D::new(run.x, run.baseline);
+
+        let glyphs = &text.glyphs[run.glyphs.clone()];
+        let advances = &text.advances[run.glyphs.clone()];
+        let offsets = &text.offsets[run.glyphs.clone()];
+        let glyphs = glyphs.iter().zip(advances).zip(offsets).map(|((glyph, advance), &(dx, dy))| {
             let gi = GlyphInstance { index: *glyph as u32,
-                                     point: pt, };
-            // pt.x += *arg.1;
-            pt.x += chunk.char_width;
+                                     point: pt + LayoutVector2D::new(dx, dy), };
+            pt.x += *advance;
             gi
         }).collect::<Vec<_>>();
 
-        // TODO fix random *1.5
-        let rect = LayoutRect::new(LayoutPoint::new(text_start_x, pt.y - chunk.height),
-                                   LayoutSize::new((glyphs.len() as f32)*chunk.char_width,chunk.height*1.2));
         let info = LayoutPrimitiveInfo::new(rect);
 
-        if let Some(color) = chunk.bg_color {
-            let rect = rect.translate(&LayoutVector2D::new(0.0, chunk.height * 0.1));
+        if let Some(color) = style.style.bg_color {
+            let rect = rect.translate(&LayoutVector2D::new(0.0, run.height * 0.1));
             let rect = rect.inflate(1.5,0.0);
             let clip = ComplexClipRegion {
                 rect, radii: BorderRadius::uniform(5.0),
@@ -430,92 +663,167 @@ impl BuiltTextBlock {
         };
         builder.push_text(&info,
              &glyphs,
-             chunk.font_instance,
-             chunk.color,
+             style.font_instance,
+             style.style.color,
              Some(options));
-        pt
     }
-}
 
-impl BuiltImageBlock {
-    pub fn new(api: &RenderApi) -> BuiltImageBlock {
-        BuiltImageBlock {
-            key: api.generate_image_key(),
-            dimensions: LayoutSize::new(0.0,0.0),
+    pub fn draw_table(&self, builder: &mut DisplayListBuilder, table: &TableLayout, images: &ImageKeys, origin: LayoutPoint) {
+        self.draw_quote_rules(builder, table.quote_depth, origin, table.height);
+
+        let grid_origin = origin + LayoutVector2D::new(table.indent, 0.0);
+        let header_height = table.row_heights.first().cloned().unwrap_or(0.0);
+        let header_rect = LayoutRect::new(grid_origin, LayoutSize::new(table.width, header_height));
+        builder.push_rect(&PrimitiveInfo::new(header_rect), self.table_header_bg);
+
+        for (cell, x, y) in table.cells() {
+            self.draw_text(builder, cell, images, origin + LayoutVector2D::new(x, y));
         }
+
+        self.draw_grid(builder, table, grid_origin);
     }
 
-    fn is_image_opaque(format: ImageFormat, bytes: &[u8]) -> bool {
-        match format {
-            ImageFormat::BGRA8 => {
-                let mut is_opaque = true;
-                for i in 0..(bytes.len() / 4) {
-                    if bytes[i * 4 + 3] != 255 {
-                        is_opaque = false;
-                        break;
-                    }
-                }
-                is_opaque
+    /// Draws the image, or the box shown in its place while it loads or if it couldn't be, and its caption
+    pub fn draw_image(&self, builder: &mut DisplayListBuilder, image: &BuiltImageBlock, images: &ImageKeys,
+                      origin: LayoutPoint, width: f32) {
+        let image_height = image.image_height(width);
+        self.draw_quote_rules(builder, image.source.quote_depth, origin, image.height(width));
+
+        let size = image.display_size(width);
+        let origin = origin + LayoutVector2D::new(image.indent(), 0.0);
+        let width = width - image.indent();
+        match (&image.placeholder, image.key, size) {
+            (&Some(ref label), _, _) => {
+                self.draw_box(builder, LayoutRect::new(origin, LayoutSize::new(width, image_height)));
+                let padding = LayoutVector2D::new(PLACEHOLDER_PADDING, PLACEHOLDER_PADDING);
+                self.draw_text(builder, label, images, origin + padding);
+            }
+            (&None, Some(key), Some(size)) => {
+                let rect = LayoutRect::new(origin + LayoutVector2D::new(self.image_x(width, size.width), 0.0), size);
+                builder.push_image(&PrimitiveInfo::new(rect), size, LayoutSize::zero(), ImageRendering::Auto, key);
             }
-            ImageFormat::RGB8 => true,
-            ImageFormat::RG8 => true,
-            ImageFormat::A8 => false,
-            ImageFormat::Invalid | ImageFormat::RGBAF32 => unreachable!(),
+            (&None, _, size) => {
+                let size = size.unwrap_or_else(|| LayoutSize::new(width, image_height));
+                let x = self.image_x(width, size.width);
+                self.draw_box(builder, LayoutRect::new(origin + LayoutVector2D::new(x, 0.0), size));
+            }
+        }
+
+        if let Some(ref caption) = image.caption {
+            let x = self.image_x(width, caption.content_width);
+            self.draw_text(builder, caption, images, origin + LayoutVector2D::new(x, image_height + CAPTION_GAP));
+        }
+    }
+
+    /// Where something `content_width` wide that's aligned like an image goes in a column `width` wide
+    fn image_x(&self, width: f32, content_width: f32) -> f32 {
+        match self.image_align {
+            ImageAlign::Left => 0.0,
+            ImageAlign::Center => ((width - content_width) / 2.0).max(0.0).round(),
         }
     }
 
-    pub fn premultiply(data: &mut [u8]) {
-        for pixel in data.chunks_mut(4) {
-            let a = pixel[3] as u32;
-            let b = pixel[2] as u32;
-            let g = pixel[1] as u32;
-            let r = pixel[0] as u32;
+    fn draw_grid(&self, builder: &mut DisplayListBuilder, table: &TableLayout, origin: LayoutPoint) {
+        const LINE_WIDTH: f32 = 1.0;
+        let mut y = origin.y;
+        for row_height in Some(&0.0).into_iter().chain(table.row_heights.iter()) {
+            y += *row_height;
+            let rect = LayoutRect::new(LayoutPoint::new(origin.x, y.min(origin.y + table.height - LINE_WIDTH)),
+                                       LayoutSize::new(table.width, LINE_WIDTH));
+            builder.push_rect(&PrimitiveInfo::new(rect), self.table_border);
+        }
 
-            pixel[3] = a as u8;
-            pixel[2] = ((r * a + 128) / 255) as u8;
-            pixel[1] = ((g * a + 128) / 255) as u8;
-            pixel[0] = ((b * a + 128) / 255) as u8;
+        let mut x = origin.x;
+        for col_width in Some(&(-CELL_PADDING * 2.0)).into_iter().chain(table.col_widths.iter()) {
+            x += col_width + CELL_PADDING * 2.0;
+            let rect = LayoutRect::new(LayoutPoint::new(x.min(origin.x + table.width - LINE_WIDTH), origin.y),
+                                       LayoutSize::new(LINE_WIDTH, table.height));
+            builder.push_rect(&PrimitiveInfo::new(rect), self.table_border);
         }
     }
+}
 
-    pub fn load(md_path: &Path, path: &str) -> (ImageDescriptor, ImageData) {
-        let mut full_path = PathBuf::new();
-        full_path.push(md_path);
-        full_path.push(path);
-        let image = image::open(full_path).unwrap();
-        let image_dims = image.dimensions();
-        let format = match image {
-            image::ImageLuma8(_) => ImageFormat::A8,
-            image::ImageRgb8(_) => ImageFormat::RGB8,
-            image::ImageRgba8(_) => ImageFormat::BGRA8,
-            _ => panic!("Unsupported format"),
+const PLACEHOLDER_PADDING: f32 = 10.0;
+/// Space reserved for an image before anything is known about its size
+const PENDING_HEIGHT: f32 = 120.0;
+const CAPTION_GAP: f32 = 6.0;
+
+impl BuiltImageBlock {
+    pub fn new(image: &ImageBlock, theme: &BuiltTheme, width: f32) -> BuiltImageBlock {
+        let mut built = BuiltImageBlock {
+            source: image.clone(),
+            key: None,
+            dimensions: None,
+            caption: None,
+            placeholder: None,
+            failure: None,
+            zoom: theme.zoom,
         };
-        let mut bytes = image.raw_pixels();
-        if format == ImageFormat::BGRA8 {
-            Self::premultiply(bytes.as_mut_slice());
+        built.relayout(theme, width);
+        built
+    }
+
+    /// Lays the caption and placeholder out again, after the theme's text size changed
+    pub fn relayout(&mut self, theme: &BuiltTheme, width: f32) {
+        self.zoom = theme.zoom;
+        let width = width - self.indent();
+        self.caption = if self.source.title.is_empty() {
+            None
+        } else {
+            let block = layout::plain_block(self.source.title.clone(), TextKind::Caption);
+            Some(layout::layout_text(&block, theme, width))
+        };
+        self.placeholder = self.failure.as_ref().map(|failure| {
+            let label = if self.source.alt.is_empty() {
+                failure.clone()
+            } else {
+                format!("{}\n{}", self.source.alt, failure)
+            };
+            let label = layout::plain_block(label, TextKind::ParagraphItalic);
+            layout::layout_text(&label, theme, width - PLACEHOLDER_PADDING * 2.0)
+        });
+    }
+
+    pub fn descriptor(image: &DecodedImage) -> ImageDescriptor {
+        let format = match image.format {
+            PixelFormat::Rgb8 => ImageFormat::RGB8,
+            PixelFormat::Bgra8 => ImageFormat::BGRA8,
+        };
+        ImageDescriptor::new(image.width, image.height, format, image.opaque)
+    }
+
+    /// Shows the alt text and why the image couldn't be loaded in its place
+    pub fn fail(&mut self, err: &ImageError, theme: &BuiltTheme, width: f32) {
+        self.failure = Some(err.to_string());
+        self.relayout(theme, width);
+    }
+
+    /// Height of the image or placeholder without the caption
+    fn image_height(&self, width: f32) -> f32 {
+        match (&self.placeholder, self.display_size(width)) {
+            (&Some(ref label), _) => label.height + PLACEHOLDER_PADDING * 2.0,
+            (&None, Some(size)) => size.height,
+            (&None, None) => PENDING_HEIGHT,
         }
-        let descriptor = ImageDescriptor::new(image_dims.0,
-                                              image_dims.1,
-                                              format,
-                                              Self::is_image_opaque(format, &bytes[..]));
-        let data = ImageData::new(bytes);
-        (descriptor, data)
+    }
+
+    /// Space left of the image for the blockquotes it's in
+    pub fn indent(&self) -> f32 {
+        layout::indent_for(self.source.quote_depth, 0)
+    }
+
+    /// Size the image is drawn at in a column `width` wide, once its natural size is known
+    pub fn display_size(&self, width: f32) -> Option<LayoutSize> {
+        let width = width - self.indent();
+        self.dimensions.map(|natural| {
+            let natural = (natural.width, natural.height);
+            let (width, height) = layout::image_display_size(&self.source, natural, width / self.zoom);
+            LayoutSize::new(width * self.zoom, height * self.zoom)
+        })
     }
 
     pub fn height(&self, width: f32) -> f32 {
-        width * (self.dimensions.height / self.dimensions.width)
-    }
-
-    pub fn draw(&self, builder: &mut DisplayListBuilder, origin: LayoutPoint, width: f32) {
-        let height = self.height(width);
-        let size = LayoutSize::new(width, height);
-        let rect = LayoutRect::new(origin, size);
-        builder.push_image(
-            &PrimitiveInfo::new(rect),
-            size,
-            LayoutSize::new(0.0, 0.0),
-            ImageRendering::Auto,
-            self.key
-        );
+        let caption_height = self.caption.as_ref().map_or(0.0, |caption| caption.height + CAPTION_GAP);
+        self.image_height(width) + caption_height
     }
 }
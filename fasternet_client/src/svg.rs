@@ -0,0 +1,231 @@
+//! Rasterizes SVG images. Unlike other images they stay sharp at any size,
+//! so they're drawn at exactly the size they're shown at, and drawn again
+//! when that changes.
+
+use images::{DecodedImage, PixelFormat};
+use resvg::{self, tiny_skia, usvg};
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
+/// Textures bigger than this don't fit on a lot of GPUs
+const MAX_SIDE: u32 = 8192;
+
+/// Whether an image's name says it's an SVG, ignoring any query or fragment
+pub fn has_svg_extension(path: &str) -> bool {
+    let path = path.split(['?', '#']).next().unwrap_or(path).to_ascii_lowercase();
+    path.ends_with(".svg") || path.ends_with(".svgz")
+}
+
+/// Whether `bytes` read from `path` are an SVG. Servers don't always put the
+/// extension in the URL, but no other image format starts with a tag.
+pub fn is_svg(path: &str, bytes: &[u8]) -> bool {
+    let text = bytes.strip_prefix(b"\xef\xbb\xbf").unwrap_or(bytes);
+    has_svg_extension(path) || text.iter().find(|byte| !byte.is_ascii_whitespace()) == Some(&b'<')
+}
+
+/// The size the SVG asks to be shown at, from the `width` and `height` or
+/// `viewBox` of its root tag. Only that tag is looked at, so sizes given in
+/// units other than pixels aren't known until the whole SVG is parsed.
+pub fn size(bytes: &[u8]) -> Option<(u32, u32)> {
+    let text = String::from_utf8_lossy(bytes);
+    let tag = root_tag(&text)?;
+    let length = |name| attribute(tag, name).and_then(pixels);
+    let view_box = attribute(tag, "viewBox").and_then(|value| {
+        let numbers: Vec<f32> = value.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|number| !number.is_empty())
+            .map(|number| number.parse().ok())
+            .collect::<Option<_>>()?;
+        match numbers[..] {
+            [_, _, width, height] if width > 0.0 && height > 0.0 => Some((width, height)),
+            _ => None,
+        }
+    });
+    let (width, height) = match (length("width"), length("height"), view_box) {
+        (Some(width), Some(height), _) => (width, height),
+        (Some(width), None, Some((box_width, box_height))) => (width, width * box_height / box_width),
+        (None, Some(height), Some((box_width, box_height))) => (height * box_width / box_height, height),
+        (None, None, Some(view_box)) => view_box,
+        _ => return None,
+    };
+    usvg::Size::from_wh(width, height).map(natural_size)
+}
+
+/// The attributes of the `<svg>` tag, after any XML declaration, comments and doctype
+fn root_tag(text: &str) -> Option<&str> {
+    let mut rest = text.trim_start_matches('\u{feff}');
+    loop {
+        rest = rest.trim_start();
+        let end = if rest.starts_with("<?") {
+            "?>"
+        } else if rest.starts_with("<!--") {
+            "-->"
+        } else if rest.starts_with("<!") {
+            ">"
+        } else {
+            break;
+        };
+        rest = &rest[rest.find(end)? + end.len()..];
+    }
+    let tag = rest.strip_prefix("<svg")?;
+    if !tag.starts_with(|c: char| c.is_whitespace() || c == '>' || c == '/') {
+        return None;
+    }
+    tag.find('>').map(|end| &tag[..end])
+}
+
+/// The value of an attribute in the text of a start tag
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = tag;
+    while let Some(at) = rest.find(name) {
+        let after_space = rest[..at].ends_with(char::is_whitespace);
+        rest = &rest[at + name.len()..];
+        let value = match rest.trim_start().strip_prefix('=') {
+            Some(value) if after_space => value.trim_start(),
+            _ => continue,
+        };
+        let quote = value.chars().next().filter(|&c| c == '"' || c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+/// A length with no unit or in pixels
+fn pixels(length: &str) -> Option<f32> {
+    let length = length.trim();
+    let number = length.strip_suffix("px").unwrap_or(length);
+    number.parse().ok().filter(|&pixels: &f32| pixels > 0.0)
+}
+
+/// Draws the SVG `max_width` pixels wide, or at its own size if that's
+/// `u32::MAX`. Files it references are read relative to `resources_dir`, and
+/// not at all without one, so a remote SVG can't pull in local files.
+pub fn rasterize(bytes: &[u8], resources_dir: Option<&Path>, max_width: u32) -> Result<DecodedImage, usvg::Error> {
+    let mut options = usvg::Options {
+        resources_dir: resources_dir.map(|dir| dir.to_owned()),
+        fontdb: system_fonts(),
+        ..usvg::Options::default()
+    };
+    if resources_dir.is_none() {
+        options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    }
+    let tree = usvg::Tree::from_data(bytes, &options)?;
+    let size = tree.size();
+    let (natural_width, natural_height) = natural_size(size);
+
+    let wanted = if max_width == u32::MAX { size.width() } else { max_width as f32 };
+    let scale = (wanted / size.width())
+        .min(MAX_SIDE as f32 / size.width())
+        .min(MAX_SIDE as f32 / size.height());
+    let width = ((size.width() * scale).round() as u32).max(1);
+    let height = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or(usvg::Error::InvalidSize)?;
+    resvg::render(&tree, tiny_skia::Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    // tiny-skia already premultiplies, so it only needs swizzling to BGRA
+    let mut bytes = pixmap.take();
+    for pixel in bytes.chunks_mut(4) {
+        pixel.swap(0, 2);
+    }
+    let opaque = bytes.chunks(4).all(|pixel| pixel[3] == 255);
+    Ok(DecodedImage {
+        width, height, natural_width, natural_height,
+        format: PixelFormat::Bgra8, opaque, bytes, frames: Vec::new(),
+    })
+}
+
+fn natural_size(size: usvg::Size) -> (u32, u32) {
+    ((size.width().ceil() as u32).max(1), (size.height().ceil() as u32).max(1))
+}
+
+/// Fonts for text in diagrams. Finding them takes a while, so it's only done
+/// once, the first time an SVG is drawn.
+fn system_fonts() -> Arc<usvg::fontdb::Database> {
+    static FONTS: OnceLock<Arc<usvg::fontdb::Database>> = OnceLock::new();
+    FONTS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+        Arc::new(fonts)
+    }).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Red on the left half, transparent on the right
+    const HALF_RED: &[u8] = br##"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="50">
+        <rect width="50" height="50" fill="#ff0000"/>
+    </svg>"##;
+
+    fn pixel(image: &DecodedImage, x: u32, y: u32) -> &[u8] {
+        let start = ((y * image.width + x) * 4) as usize;
+        &image.bytes[start..start + 4]
+    }
+
+    #[test]
+    fn detects_svgs() {
+        assert!(has_svg_extension("docs/Diagram.SVG"));
+        assert!(has_svg_extension("https://example.com/chart.svgz?raw=true#top"));
+        assert!(!has_svg_extension("https://example.com/badge?style=svg"));
+        assert!(is_svg("https://example.com/badge", b"\xef\xbb\xbf\n  <?xml version=\"1.0\"?><svg/>"));
+        assert!(!is_svg("logo.png", b"\x89PNG\r\n"));
+    }
+
+    #[test]
+    fn rasterizes_at_requested_width() {
+        let image = rasterize(HALF_RED, None, 300).unwrap();
+        assert_eq!((image.width, image.height), (300, 150));
+        assert_eq!((image.natural_width, image.natural_height), (100, 50));
+        assert_eq!(image.bytes.len(), 300 * 150 * 4);
+
+        let image = rasterize(HALF_RED, None, 30).unwrap();
+        assert_eq!((image.width, image.height), (30, 15));
+
+        let image = rasterize(HALF_RED, None, u32::MAX).unwrap();
+        assert_eq!((image.width, image.height), (100, 50));
+    }
+
+    #[test]
+    fn premultiplied_bgra() {
+        let image = rasterize(HALF_RED, None, 200).unwrap();
+        assert_eq!(image.format, PixelFormat::Bgra8);
+        assert!(!image.opaque);
+        assert_eq!(pixel(&image, 10, 50), &[0, 0, 255, 255]);
+        assert_eq!(pixel(&image, 190, 50), &[0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn view_box_size() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 40.5 20"/>"#;
+        assert_eq!(size(svg), Some((41, 20)));
+        let image = rasterize(svg, None, 81).unwrap();
+        assert_eq!((image.width, image.height), (81, 40));
+    }
+
+    #[test]
+    fn size_from_root_tag() {
+        assert_eq!(size(HALF_RED), Some((100, 50)));
+        let svg = br#"<?xml version="1.0"?>
+            <!-- <svg width="1" height="1"> -->
+            <svg xmlns="http://www.w3.org/2000/svg" stroke-width='3' width='80px' viewBox="0,0,40,10">"#;
+        assert_eq!(size(svg), Some((80, 20)));
+        // only the root tag is read, so the rest can be anything
+        assert_eq!(size(br#"<svg height="30" viewBox="0 0 20 10"><not closed"#), Some((60, 30)));
+        assert_eq!(size(br#"<svg width="50%" height="20"/>"#), None);
+        assert_eq!(size(br#"<svgx width="5" height="5"/>"#), None);
+    }
+
+    #[test]
+    fn stays_under_texture_limit() {
+        let svg = br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="100"/>"#;
+        let image = rasterize(svg, None, 1000).unwrap();
+        assert_eq!((image.width, image.height), (MAX_SIDE / 10, MAX_SIDE));
+    }
+
+    #[test]
+    fn invalid_svg() {
+        assert_eq!(size(b"<svg"), None);
+        assert!(rasterize(b"<html></html>", None, 100).is_err());
+    }
+}
@@ -0,0 +1,135 @@
+//! Just enough HTML to pick images out of it. READMEs often use `<img>`
+//! instead of Markdown images to give them a size.
+
+use super::ImageBlock;
+
+/// Finds every `<img>` tag with a `src` in a snippet of HTML
+pub fn images(html: &str) -> Vec<ImageBlock> {
+    // ASCII lowercasing keeps byte offsets the same
+    let lower = html.to_ascii_lowercase();
+    let mut images = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find("<img") {
+        let start = pos + found + "<img".len();
+        let ends_name = html[start..].chars().next().map(|c| c.is_whitespace() || c == '/' || c == '>');
+        if ends_name != Some(true) {
+            pos = start;
+            continue;
+        }
+        let (attrs, len) = attributes(&html[start..]);
+        pos = start + len;
+
+        let attr = |name: &str| attrs.iter().find(|attr| attr.0 == name).map(|attr| attr.1.clone());
+        if let Some(path) = attr("src") {
+            images.push(ImageBlock {
+                path,
+                alt: attr("alt").unwrap_or_default(),
+                title: attr("title").unwrap_or_default(),
+                width: attr("width").and_then(|width| dimension(&width)),
+                height: attr("height").and_then(|height| dimension(&height)),
+                quote_depth: 0,
+            });
+        }
+    }
+    images
+}
+
+/// Parses attributes up to the end of the tag, returning them with lowercased
+/// names and how many bytes were used
+fn attributes(tag: &str) -> (Vec<(String, String)>, usize) {
+    let mut attrs = Vec::new();
+    let mut chars = tag.char_indices().peekable();
+    loop {
+        while let Some(&(_, c)) = chars.peek() {
+            if !c.is_whitespace() && c != '/' { break; }
+            chars.next();
+        }
+        let name_start = match chars.peek() {
+            Some(&(_, '>')) => return (attrs, chars.next().unwrap().0 + 1),
+            Some(&(i, _)) => i,
+            None => return (attrs, tag.len()),
+        };
+        let mut name_end = tag.len();
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() || c == '=' || c == '>' || c == '/' {
+                name_end = i;
+                break;
+            }
+            chars.next();
+        }
+        let name = tag[name_start..name_end].to_ascii_lowercase();
+
+        let mut value = String::new();
+        if let Some(&(_, '=')) = chars.peek() {
+            chars.next();
+            let quote = match chars.peek() {
+                Some(&(_, c)) if c == '"' || c == '\'' => {
+                    chars.next();
+                    Some(c)
+                }
+                _ => None,
+            };
+            while let Some(&(_, c)) = chars.peek() {
+                let ends = match quote {
+                    Some(quote) => c == quote,
+                    None => c.is_whitespace() || c == '>',
+                };
+                if ends { break; }
+                value.push(c);
+                chars.next();
+            }
+            if quote.is_some() {
+                chars.next();
+            }
+        }
+        attrs.push((name, unescape(&value)));
+    }
+}
+
+/// Decodes the entities that show up in URLs and alt text
+fn unescape(value: &str) -> String {
+    value.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Reads a size in pixels like `200` or `200px`, percentages aren't supported
+fn dimension(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let value = value.strip_suffix("px").unwrap_or(value);
+    value.trim().parse().ok().filter(|&pixels| pixels > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(path: &str, width: Option<u32>, height: Option<u32>) -> ImageBlock {
+        ImageBlock { path: path.to_string(), alt: String::new(), title: String::new(), width, height, quote_depth: 0 }
+    }
+
+    #[test]
+    fn attribute_syntax() {
+        let html = r#"<p align="center"><IMG SRC='logo.png' Width=200 height="100px"/></p>"#;
+        assert_eq!(images(html), vec![image("logo.png", Some(200), Some(100))]);
+
+        let html = r#"<img alt="a &quot;quoted&quot; > sign" src="a.png?x=1&amp;y=2" title=hi>"#;
+        let found = images(html);
+        assert_eq!(found[0].path, "a.png?x=1&y=2");
+        assert_eq!(found[0].alt, "a \"quoted\" > sign");
+        assert_eq!(found[0].title, "hi");
+    }
+
+    #[test]
+    fn several_images() {
+        let html = "<a href=\"x\"><img src=\"a.png\" width=\"50%\"></a> <imgx src=\"no.png\"> <img alt=\"no src\"> <img src=b.svg>";
+        assert_eq!(images(html), vec![image("a.png", None, None), image("b.svg", None, None)]);
+    }
+
+    #[test]
+    fn unterminated_tag() {
+        assert_eq!(images("<img src=\"a.png\" width=\"1"), vec![image("a.png", Some(1), None)]);
+    }
+}
@@ -1,6 +1,13 @@
 extern crate pulldown_cmark;
+extern crate regex;
 
+mod html;
 pub mod markdown;
+pub mod outline;
+pub mod search;
+
+use std::ops::Range;
+use std::slice;
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct ResourceId {
@@ -28,26 +35,85 @@ pub struct TextBlock {
     pub content: String,
     pub chunks: Vec<Chunk>,
     pub bg: BlockBackground,
-    // link_dests: Vec<ResourceLink>,
+    /// How many blockquotes this block is nested inside of
+    pub quote_depth: u8,
+    /// How many lists this block is nested inside of
+    pub list_level: u8,
+    /// Set on the first block of each list item
+    pub list_marker: Option<ListMarker>,
+    /// Destinations of the links in this block, indexed by `Chunk::link`
+    pub links: Vec<Link>,
+    /// One for each `INLINE_IMAGE` char in the content, in order
+    pub images: Vec<ImageBlock>,
+    /// The language named after the fence of a fenced code block
+    pub language: Option<String>,
+    /// Set on headings, whatever their text is styled as
+    pub heading_level: Option<u8>,
+}
+
+/// Stands in for an inline image in the content of a `TextBlock`, always in
+/// a chunk of kind `TextKind::InlineImage`
+pub const INLINE_IMAGE: char = '\u{fffc}';
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Link {
+    pub dest: String,
+    pub title: String,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub struct ImageBlock {
     pub path: String,
+    /// Plain text of the image description, without any formatting
+    pub alt: String,
+    pub title: String,
+    /// Size asked for by an HTML `<img>`, in pixels
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// How many blockquotes an image shown as its own block is nested inside of
+    pub quote_depth: u8,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct TableBlock {
+    pub alignments: Vec<Alignment>,
+    pub header: Vec<TextBlock>,
+    /// Every row has exactly one cell per entry in `alignments`
+    pub rows: Vec<Vec<TextBlock>>,
+    /// How many blockquotes this table is nested inside of
+    pub quote_depth: u8,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 pub enum Block {
     Text(TextBlock),
     Image(ImageBlock),
+    Table(TableBlock),
 }
 
+/// A run of identically styled text. The chunks of a block tile its content
+/// end to end, so only lengths are stored; runs longer than `u16::MAX` bytes
+/// are split into several chunks, see `Chunk::push_run`.
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub struct Chunk {
-    pub start: u16,
-    pub end: u16,
+    pub len: u16,
     pub kind: TextKind,
-    // link_num: u8,
+    pub link: Option<u16>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum ListKind {
+    Bullet,
+    Ordered,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct ListMarker {
+    pub kind: ListKind,
+    /// Position in the list, counting from the start number for ordered lists
+    pub number: usize,
+    /// `Some` for task list items
+    pub checked: Option<bool>,
 }
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
@@ -56,13 +122,103 @@ pub enum BlockBackground {
     Code,
 }
 
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub enum Alignment {
+    None,
+    Left,
+    Center,
+    Right,
+}
+
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
 pub enum TextKind {
     Header1,
     Header2,
+    Header3,
+    Header4,
+    Header5,
+    Header6,
     Paragraph,
     ParagraphBold,
     ParagraphItalic,
     ParagraphCode,
+    CodeBlock,
     Link,
+    /// Image titles shown under the image
+    Caption,
+    /// Images inside a paragraph, see `INLINE_IMAGE`
+    InlineImage,
+    /// Tokens of a highlighted code block. Everything else in it stays `CodeBlock`.
+    CodeComment,
+    CodeKeyword,
+    CodeString,
+    /// Numbers and built in values like `true`
+    CodeConstant,
+    CodeType,
+    CodeFunction,
+}
+
+impl TextKind {
+    pub fn heading_level(&self) -> Option<u8> {
+        match *self {
+            TextKind::Header1 => Some(1),
+            TextKind::Header2 => Some(2),
+            TextKind::Header3 => Some(3),
+            TextKind::Header4 => Some(4),
+            TextKind::Header5 => Some(5),
+            TextKind::Header6 => Some(6),
+            _ => None,
+        }
+    }
+
+    pub fn heading(level: i32) -> TextKind {
+        match level {
+            1 => TextKind::Header1,
+            2 => TextKind::Header2,
+            3 => TextKind::Header3,
+            4 => TextKind::Header4,
+            5 => TextKind::Header5,
+            _ => TextKind::Header6,
+        }
+    }
+}
+
+impl TextBlock {
+    /// The byte range of `content` covered by each chunk
+    pub fn chunk_ranges(&self) -> ChunkRanges<'_> {
+        ChunkRanges { chunks: self.chunks.iter(), offset: 0 }
+    }
+}
+
+impl Chunk {
+    /// Appends chunks covering `run`, splitting it on char boundaries if it's
+    /// too long for a single chunk.
+    pub fn push_run(chunks: &mut Vec<Chunk>, mut run: &str, kind: TextKind, link: Option<u16>) {
+        while run.len() > u16::MAX as usize {
+            let mut split = u16::MAX as usize;
+            while !run.is_char_boundary(split) {
+                split -= 1;
+            }
+            chunks.push(Chunk { len: split as u16, kind, link });
+            run = &run[split..];
+        }
+        chunks.push(Chunk { len: run.len() as u16, kind, link });
+    }
+}
+
+pub struct ChunkRanges<'a> {
+    chunks: slice::Iter<'a, Chunk>,
+    offset: usize,
+}
+
+impl<'a> Iterator for ChunkRanges<'a> {
+    type Item = (Range<usize>, &'a Chunk);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks.next().map(|chunk| {
+            let start = self.offset;
+            self.offset += chunk.len as usize;
+            (start..self.offset, chunk)
+        })
+    }
 }
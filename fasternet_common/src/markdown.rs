@@ -1,21 +1,74 @@
-use pulldown_cmark::{Parser, Event, Tag};
+use pulldown_cmark::{self, Parser, Event, Tag, OPTION_ENABLE_TABLES};
 use std::mem;
 use super::*;
 
-fn end_block(blocks: &mut Vec<Block>, cur_text: &mut String, chunks: &mut Vec<Chunk>, bg: BlockBackground) {
-    let block = TextBlock {
-        content: mem::replace(cur_text, String::new()),
-        chunks: mem::replace(chunks, Vec::new()),
-        bg,
-    };
-    blocks.push(Block::Text(block));
+/// Cuts a block down to a byte range of its content, shifting and clipping
+/// the chunks to match.
+fn slice_block(mut block: TextBlock, start: usize, end: usize) -> TextBlock {
+    let chunks = block.chunk_ranges().filter_map(|(range, chunk)| {
+        let chunk_start = range.start.max(start).min(end);
+        let chunk_end = range.end.max(start).min(end);
+        if chunk_start == chunk_end { return None; }
+        Some(Chunk { len: (chunk_end - chunk_start) as u16, ..*chunk })
+    }).collect();
+    block.chunks = chunks;
+    block.content = block.content[start..end].to_string();
+    block
+}
+
+/// Table cells come with the padding around the pipes included, strip it off.
+fn trim_cell(cell: TextBlock) -> TextBlock {
+    let start = cell.content.len() - cell.content.trim_start().len();
+    let end = cell.content.trim_end().len().max(start);
+    slice_block(cell, start, end)
+}
+
+/// pulldown-cmark doesn't know about task lists, so they show up as a
+/// literal `[ ] ` or `[x] ` at the start of the item text.
+fn task_state(content: &str) -> Option<bool> {
+    if content.starts_with("[ ] ") {
+        Some(false)
+    } else if content.starts_with("[x] ") || content.starts_with("[X] ") {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+fn convert_alignment(alignment: &pulldown_cmark::Alignment) -> Alignment {
+    match *alignment {
+        pulldown_cmark::Alignment::None => Alignment::None,
+        pulldown_cmark::Alignment::Left => Alignment::Left,
+        pulldown_cmark::Alignment::Center => Alignment::Center,
+        pulldown_cmark::Alignment::Right => Alignment::Right,
+    }
+}
+
+fn plain_block(content: String, chunks: Vec<Chunk>, links: Vec<Link>, images: Vec<ImageBlock>) -> TextBlock {
+    TextBlock {
+        content, chunks, links, images,
+        bg: BlockBackground::NoBackground,
+        language: None,
+        quote_depth: 0,
+        list_level: 0,
+        list_marker: None,
+        heading_level: None,
+    }
+}
+
+/// The first word of a code fence's info string, so ```` ```rust,ignore ```` is Rust
+fn code_language(info: &str) -> Option<String> {
+    info.split(|c: char| c.is_whitespace() || c == ',')
+        .next()
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_string())
 }
 
 fn tag_style(tag: &Tag) -> Option<TextKind> {
     match *tag {
-        Tag::Paragraph | Tag::CodeBlock(_) | Tag::List(_) => Some(TextKind::Paragraph),
-        Tag::Header(1) => Some(TextKind::Header1),
-        Tag::Header(_) => Some(TextKind::Header2),
+        Tag::Paragraph | Tag::TableCell => Some(TextKind::Paragraph),
+        Tag::CodeBlock(_) => Some(TextKind::CodeBlock),
+        Tag::Header(level) => Some(TextKind::heading(level)),
         Tag::Link(_,_) => Some(TextKind::Link),
         Tag::Strong => Some(TextKind::ParagraphBold),
         Tag::Emphasis => Some(TextKind::ParagraphItalic),
@@ -24,77 +77,275 @@ fn tag_style(tag: &Tag) -> Option<TextKind> {
     }
 }
 
-fn add_chunk(chunks: &mut Vec<Chunk>, stack: &mut Vec<TextKind>, last_chunk: &mut usize, len: usize) {
-    if stack.is_empty() { return; }
-    let chunk = Chunk {
-        start: *last_chunk as u16,
-        end: len as u16,
-        kind: stack.last().unwrap().clone(),
-    };
-    *last_chunk = len;
-    chunks.push(chunk);
+fn is_inline(tag: &Tag) -> bool {
+    matches!(*tag, Tag::Emphasis | Tag::Strong | Tag::Code | Tag::Link(_,_) | Tag::Image(_,_))
 }
 
-pub fn parse_markdown(document: &str) -> Vec<Block> {
-    let parser = Parser::new(document);
+struct ListState {
+    kind: ListKind,
+    next_number: usize,
+}
 
-    let mut blocks = Vec::new();
-    let mut cur_text = String::new();
+struct BlockBuilder {
+    blocks: Vec<Block>,
+    cur_text: String,
+    chunks: Vec<Chunk>,
+    stack: Vec<TextKind>,
+    last_chunk: usize,
+    links: Vec<Link>,
+    /// Index into `links` while inside a link
+    cur_link: Option<u16>,
+    images: Vec<ImageBlock>,
+    table: Option<TableBlock>,
+    row: Vec<TextBlock>,
+    quote_depth: u8,
+    lists: Vec<ListState>,
+    /// Set when a list item starts and taken by the first block inside it
+    pending_marker: Option<ListMarker>,
+    /// Items in tight lists have their text directly inside the item rather
+    /// than in a paragraph, so we open one ourselves.
+    implicit_paragraph: bool,
+    /// Alt text collected while inside an image, it isn't part of the surrounding text
+    image_alt: Option<String>,
+    /// Set when a heading starts and taken by the block it ends
+    heading_level: Option<u8>,
+}
 
-    let mut last_chunk = 0;
-    let mut chunks = Vec::new();
-    let mut stack = Vec::new();
-    for event in parser {
-        // println!("{:?}", event);
-        match event {
-            Event::Text(txt) => cur_text.push_str(&txt),
-            Event::Start(tag) => {
-                if let Some(style) = tag_style(&tag) {
-                    if stack.is_empty() {
-                        cur_text.clear();
-                        chunks.clear();
-                        stack.clear();
-                        last_chunk = 0;
-                    } else {
-                        add_chunk(&mut chunks, &mut stack, &mut last_chunk, cur_text.len());
-                    }
-                    stack.push(style);
+impl BlockBuilder {
+    fn new() -> Self {
+        BlockBuilder {
+            blocks: Vec::new(),
+            cur_text: String::new(),
+            chunks: Vec::new(),
+            stack: Vec::new(),
+            last_chunk: 0,
+            links: Vec::new(),
+            cur_link: None,
+            images: Vec::new(),
+            table: None,
+            row: Vec::new(),
+            quote_depth: 0,
+            lists: Vec::new(),
+            pending_marker: None,
+            implicit_paragraph: false,
+            image_alt: None,
+            heading_level: None,
+        }
+    }
+
+    fn add_chunk(&mut self) {
+        if self.stack.is_empty() { return; }
+        let len = self.cur_text.len();
+        if len > self.last_chunk {
+            let kind = *self.stack.last().unwrap();
+            Chunk::push_run(&mut self.chunks, &self.cur_text[self.last_chunk..len], kind, self.cur_link);
+        }
+        self.last_chunk = len;
+    }
+
+    fn push_style(&mut self, style: TextKind) {
+        if self.stack.is_empty() {
+            self.cur_text.clear();
+            self.chunks.clear();
+            self.links.clear();
+            self.images.clear();
+            self.last_chunk = 0;
+        } else {
+            self.add_chunk();
+        }
+        self.stack.push(style);
+    }
+
+    fn pop_style(&mut self) {
+        self.add_chunk();
+        self.stack.pop();
+    }
+
+    fn take_text(&mut self) -> (String, Vec<Chunk>, Vec<Link>, Vec<ImageBlock>) {
+        (mem::take(&mut self.cur_text), mem::take(&mut self.chunks), mem::take(&mut self.links), mem::take(&mut self.images))
+    }
+
+    fn end_block(&mut self, bg: BlockBackground, language: Option<String>) {
+        let (content, chunks, links, mut images) = self.take_text();
+        let heading_level = self.heading_level.take();
+        // a paragraph of just an image gets shown full width, unless it's a link or list item
+        if images.len() == 1 && content.trim() == INLINE_IMAGE.to_string() && links.is_empty()
+            && self.pending_marker.is_none() && heading_level.is_none() {
+            let image = ImageBlock { quote_depth: self.quote_depth, ..images.remove(0) };
+            self.blocks.push(Block::Image(image));
+            return;
+        }
+        let mut block = TextBlock {
+            content, chunks, links, images, bg, language, heading_level,
+            quote_depth: self.quote_depth,
+            list_level: self.lists.len() as u8,
+            list_marker: self.pending_marker.take(),
+        };
+        if let Some(ref mut marker) = block.list_marker {
+            marker.checked = task_state(&block.content);
+        }
+        if block.list_marker.and_then(|marker| marker.checked).is_some() {
+            let len = block.content.len();
+            block = slice_block(block, 4, len);
+        }
+        self.blocks.push(Block::Text(block));
+    }
+
+    fn open_implicit_paragraph(&mut self) {
+        if self.stack.is_empty() {
+            self.push_style(TextKind::Paragraph);
+            self.implicit_paragraph = true;
+        }
+    }
+
+    fn close_implicit_paragraph(&mut self) {
+        if !self.implicit_paragraph { return; }
+        self.implicit_paragraph = false;
+        self.pop_style();
+        if !self.cur_text.is_empty() {
+            self.end_block(BlockBackground::NoBackground, None);
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if let Some(ref mut alt) = self.image_alt {
+            alt.push_str(text);
+            return;
+        }
+        self.open_implicit_paragraph();
+        self.cur_text.push_str(text);
+    }
+
+    fn inline_image(&mut self, image: ImageBlock) {
+        self.images.push(image);
+        self.push_style(TextKind::InlineImage);
+        self.cur_text.push(INLINE_IMAGE);
+        self.pop_style();
+    }
+
+    /// Only `<img>` tags are kept from HTML, everything else is dropped
+    fn html(&mut self, html: &str) {
+        for image in html::images(html) {
+            self.open_implicit_paragraph();
+            self.inline_image(image);
+        }
+    }
+
+    fn start(&mut self, tag: Tag) {
+        if is_inline(&tag) {
+            self.open_implicit_paragraph();
+        } else {
+            self.close_implicit_paragraph();
+        }
+
+        if let Some(style) = tag_style(&tag) {
+            self.push_style(style);
+        }
+
+        match tag {
+            Tag::Table(ref alignments) => {
+                let alignments = alignments.iter().map(convert_alignment).collect();
+                self.table = Some(TableBlock { alignments, header: Vec::new(), rows: Vec::new(), quote_depth: self.quote_depth });
+            }
+            Tag::Header(level) => self.heading_level = Some(level as u8),
+            Tag::BlockQuote => self.quote_depth += 1,
+            Tag::Link(ref dest, ref title) => {
+                // chunks can only point at so many links, any past that are left as plain text
+                self.cur_link = if self.links.len() <= u16::MAX as usize {
+                    self.links.push(Link { dest: dest.to_string(), title: title.to_string() });
+                    Some((self.links.len() - 1) as u16)
+                } else {
+                    None
+                };
+            }
+            Tag::List(start) => {
+                let kind = if start.is_some() { ListKind::Ordered } else { ListKind::Bullet };
+                self.lists.push(ListState { kind, next_number: start.unwrap_or(1) });
+            }
+            Tag::Item => {
+                if let Some(list) = self.lists.last_mut() {
+                    self.pending_marker = Some(ListMarker { kind: list.kind, number: list.next_number, checked: None });
+                    list.next_number += 1;
                 }
+            }
+            Tag::Image(_,_) => self.image_alt = Some(String::new()),
+            _ => (),
+        }
+    }
 
-                match tag {
-                    Tag::Item => cur_text.push_str("- "),
-                    _ => (),
+    fn end(&mut self, tag: Tag) {
+        if !is_inline(&tag) {
+            self.close_implicit_paragraph();
+        }
+
+        if let Tag::Image(ref path, ref title) = tag {
+            let alt = self.image_alt.take().unwrap_or_default();
+            self.inline_image(ImageBlock {
+                path: path.to_string(), alt, title: title.to_string(),
+                width: None, height: None, quote_depth: 0,
+            });
+        }
+
+        if tag_style(&tag).is_some() {
+            self.pop_style();
+        }
+        if let Tag::Link(_,_) = tag {
+            self.cur_link = None;
+        }
+
+        match tag {
+            Tag::TableCell => {
+                let (content, chunks, links, images) = self.take_text();
+                self.row.push(trim_cell(plain_block(content, chunks, links, images)));
+            }
+            Tag::TableHead => {
+                if let Some(ref mut table) = self.table {
+                    table.header = mem::take(&mut self.row);
                 }
             }
-            Event::End(tag) => {
-                match tag {
-                    Tag::Item => cur_text.push_str("\n"),
-                    Tag::Image(ref path,_) => {
-                        blocks.push(Block::Image(ImageBlock{ path: path.to_string() }))
-                    }
-                    _ => (),
+            Tag::TableRow => {
+                if let Some(ref mut table) = self.table {
+                    let mut cells = mem::take(&mut self.row);
+                    cells.resize(table.alignments.len(), plain_block(String::new(), Vec::new(), Vec::new(), Vec::new()));
+                    table.rows.push(cells);
                 }
-
-                if let Some(_) = tag_style(&tag) {
-                    add_chunk(&mut chunks, &mut stack, &mut last_chunk, cur_text.len());
-                    stack.pop();
+            }
+            Tag::Table(_) => {
+                if let Some(table) = self.table.take() {
+                    self.blocks.push(Block::Table(table));
                 }
-
-                if stack.is_empty() && !cur_text.is_empty() {
-                    let bg = if let Tag::CodeBlock(_) = tag {
-                        BlockBackground::Code
-                    } else {
-                        BlockBackground::NoBackground
-                    };
-                    end_block(&mut blocks, &mut cur_text, &mut chunks, bg);
+            }
+            Tag::BlockQuote => self.quote_depth -= 1,
+            Tag::List(_) => { self.lists.pop(); }
+            Tag::Item => self.pending_marker = None,
+            _ => if self.stack.is_empty() && !self.cur_text.is_empty() {
+                match tag {
+                    Tag::CodeBlock(ref info) => self.end_block(BlockBackground::Code, code_language(info)),
+                    _ => self.end_block(BlockBackground::NoBackground, None),
                 }
             }
+        }
+    }
+}
+
+pub fn parse_markdown(document: &str) -> Vec<Block> {
+    let parser = Parser::new_ext(document, OPTION_ENABLE_TABLES);
+
+    let mut builder = BlockBuilder::new();
+    for event in parser {
+        // println!("{:?}", event);
+        match event {
+            Event::Text(txt) => builder.text(&txt),
+            Event::Start(tag) => builder.start(tag),
+            Event::End(tag) => builder.end(tag),
+            Event::Html(html) | Event::InlineHtml(html) => builder.html(&html),
             _ => ()
         }
     }
+    builder.close_implicit_paragraph();
 
-    // println!("{:?}", blocks);
-    blocks
+    // println!("{:?}", builder.blocks);
+    builder.blocks
 }
 
 #[cfg(test)]
@@ -110,4 +361,321 @@ mod tests {
 
         let _blocks = parse_markdown(&buffer);
     }
+
+    fn cell(content: &str, chunks: Vec<Chunk>) -> TextBlock {
+        plain_block(content.to_string(), chunks, Vec::new(), Vec::new())
+    }
+
+    fn plain(len: u16) -> Chunk {
+        Chunk { len, kind: TextKind::Paragraph, link: None }
+    }
+
+    #[test]
+    fn parse_table() {
+        let doc = "| Name | **Size** | Notes |\n\
+                   |:-----|-----:|:---:|\n\
+                   | foo | 12 | `x` |\n\
+                   | bar | 3 |\n";
+        let blocks = parse_markdown(doc);
+        let expected = TableBlock {
+            alignments: vec![Alignment::Left, Alignment::Right, Alignment::Center],
+            header: vec![
+                cell("Name", vec![plain(4)]),
+                cell("Size", vec![Chunk { len: 4, kind: TextKind::ParagraphBold, link: None }]),
+                cell("Notes", vec![plain(5)]),
+            ],
+            rows: vec![
+                vec![
+                    cell("foo", vec![plain(3)]),
+                    cell("12", vec![plain(2)]),
+                    cell("x", vec![Chunk { len: 1, kind: TextKind::ParagraphCode, link: None }]),
+                ],
+                vec![
+                    cell("bar", vec![plain(3)]),
+                    cell("3", vec![plain(1)]),
+                    cell("", vec![]),
+                ],
+            ],
+            quote_depth: 0,
+        };
+        assert_eq!(blocks, vec![Block::Table(expected)]);
+    }
+
+    #[test]
+    fn table_between_paragraphs() {
+        let doc = "before\n\n| a | b |\n|---|---|\n| c | d |\n\nafter\n";
+        let blocks = parse_markdown(doc);
+        assert_eq!(blocks.len(), 3);
+        match blocks[1] {
+            Block::Table(ref table) => {
+                assert_eq!(table.alignments, vec![Alignment::None, Alignment::None]);
+                assert_eq!(table.header, vec![cell("a", vec![plain(1)]), cell("b", vec![plain(1)])]);
+                assert_eq!(table.rows, vec![vec![cell("c", vec![plain(1)]), cell("d", vec![plain(1)])]]);
+            }
+            ref other => panic!("expected a table, got {:?}", other),
+        }
+        match blocks[2] {
+            Block::Text(ref text) => assert_eq!(text.content, "after"),
+            ref other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn nested_blockquotes() {
+        let doc = "outside\n\n> quoted\n>\n> > nested\n>\n> back\n\nafter\n";
+        let depths: Vec<(String, u8)> = parse_markdown(doc).into_iter().map(|block| match block {
+            Block::Text(text) => (text.content, text.quote_depth),
+            other => panic!("expected text, got {:?}", other),
+        }).collect();
+        assert_eq!(depths, vec![
+            ("outside".to_string(), 0),
+            ("quoted".to_string(), 1),
+            ("nested".to_string(), 2),
+            ("back".to_string(), 1),
+            ("after".to_string(), 0),
+        ]);
+    }
+
+    #[test]
+    fn quoted_image() {
+        let blocks = parse_markdown("> ![x](a.png)\n>\n> > ![y](b.png)\n\n![z](c.png)\n");
+        let depths: Vec<(String, u8)> = blocks.into_iter().map(|block| match block {
+            Block::Image(image) => (image.path, image.quote_depth),
+            other => panic!("expected an image, got {:?}", other),
+        }).collect();
+        assert_eq!(depths, vec![
+            ("a.png".to_string(), 1),
+            ("b.png".to_string(), 2),
+            ("c.png".to_string(), 0),
+        ]);
+    }
+
+    #[test]
+    fn quoted_table() {
+        let blocks = parse_markdown("> | a | b |\n> |---|---|\n> | c | d |\n\n| e | f |\n|---|---|\n| g | h |\n");
+        let depths: Vec<u8> = blocks.iter().map(|block| match *block {
+            Block::Table(ref table) => table.quote_depth,
+            ref other => panic!("expected a table, got {:?}", other),
+        }).collect();
+        assert_eq!(depths, vec![1, 0]);
+        match blocks[0] {
+            Block::Table(ref table) => assert_eq!(table.rows, vec![vec![cell("c", vec![plain(1)]), cell("d", vec![plain(1)])]]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn list_items(doc: &str) -> Vec<(String, u8, Option<ListMarker>)> {
+        parse_markdown(doc).into_iter().map(|block| match block {
+            Block::Text(text) => (text.content, text.list_level, text.list_marker),
+            other => panic!("expected text, got {:?}", other),
+        }).collect()
+    }
+
+    fn marker(kind: ListKind, number: usize, checked: Option<bool>) -> Option<ListMarker> {
+        Some(ListMarker { kind, number, checked })
+    }
+
+    #[test]
+    fn nested_lists() {
+        let doc = "- one\n- two\n  3. three\n  4. four\n- five\n";
+        assert_eq!(list_items(doc), vec![
+            ("one".to_string(), 1, marker(ListKind::Bullet, 1, None)),
+            ("two".to_string(), 1, marker(ListKind::Bullet, 2, None)),
+            ("three".to_string(), 2, marker(ListKind::Ordered, 3, None)),
+            ("four".to_string(), 2, marker(ListKind::Ordered, 4, None)),
+            ("five".to_string(), 1, marker(ListKind::Bullet, 3, None)),
+        ]);
+    }
+
+    #[test]
+    fn loose_list_continuation() {
+        let doc = "1. first\n\n   more of first\n2. second\n\nafter\n";
+        assert_eq!(list_items(doc), vec![
+            ("first".to_string(), 1, marker(ListKind::Ordered, 1, None)),
+            ("more of first".to_string(), 1, None),
+            ("second".to_string(), 1, marker(ListKind::Ordered, 2, None)),
+            ("after".to_string(), 0, None),
+        ]);
+    }
+
+    #[test]
+    fn tight_item_with_formatting() {
+        let blocks = parse_markdown("- some **bold** text\n");
+        assert_eq!(blocks, vec![Block::Text(TextBlock {
+            content: "some bold text".to_string(),
+            chunks: vec![
+                plain(5),
+                Chunk { len: 4, kind: TextKind::ParagraphBold, link: None },
+                plain(5),
+            ],
+            bg: BlockBackground::NoBackground,
+            quote_depth: 0,
+            list_level: 1,
+            list_marker: marker(ListKind::Bullet, 1, None),
+            heading_level: None,
+            links: vec![],
+            images: vec![],
+            language: None,
+        })]);
+    }
+
+    #[test]
+    fn task_list() {
+        let doc = "- [ ] todo\n- [x] done\n- plain\n";
+        assert_eq!(list_items(doc), vec![
+            ("todo".to_string(), 1, marker(ListKind::Bullet, 1, Some(false))),
+            ("done".to_string(), 1, marker(ListKind::Bullet, 2, Some(true))),
+            ("plain".to_string(), 1, marker(ListKind::Bullet, 3, None)),
+        ]);
+    }
+
+    #[test]
+    fn huge_code_block() {
+        let line = "0123456789 \u{e9}\u{e9}\u{e9} abcdefghijklmnopqrstuvwxyz\n";
+        let code: String = (0..2000).map(|_| line).collect();
+        assert!(code.len() > 64 * 1024);
+        let doc = format!("```\n{}```\n\nafter\n", code);
+        let blocks = parse_markdown(&doc);
+        assert_eq!(blocks.len(), 2);
+        let text = match blocks[0] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.content, code);
+        assert_eq!(text.bg, BlockBackground::Code);
+        assert!(text.chunks.len() > 1);
+        let mut end = 0;
+        for (range, chunk) in text.chunk_ranges() {
+            assert_eq!(range.start, end);
+            assert_eq!(chunk.kind, TextKind::CodeBlock);
+            assert!(text.content.is_char_boundary(range.end));
+            end = range.end;
+        }
+        assert_eq!(end, code.len());
+    }
+
+    #[test]
+    fn code_languages() {
+        let doc = "```rust,ignore\nfn main() {}\n```\n\n```\nplain\n```\n\n    indented\n\n``` python extra\nx = 1\n```\n";
+        let languages: Vec<Option<String>> = parse_markdown(doc).into_iter().map(|block| match block {
+            Block::Text(text) => text.language,
+            other => panic!("expected text, got {:?}", other),
+        }).collect();
+        assert_eq!(languages, vec![Some("rust".to_string()), None, None, Some("python".to_string())]);
+    }
+
+    #[test]
+    fn links() {
+        let doc = "see [the **docs**](docs/intro.md \"Intro\") or [site](https://example.com)\n";
+        let blocks = parse_markdown(doc);
+        let text = match blocks[0] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.content, "see the docs or site");
+        assert_eq!(text.links, vec![
+            Link { dest: "docs/intro.md".to_string(), title: "Intro".to_string() },
+            Link { dest: "https://example.com".to_string(), title: "".to_string() },
+        ]);
+        assert_eq!(text.chunks, vec![
+            plain(4),
+            Chunk { len: 4, kind: TextKind::Link, link: Some(0) },
+            Chunk { len: 4, kind: TextKind::ParagraphBold, link: Some(0) },
+            plain(4),
+            Chunk { len: 4, kind: TextKind::Link, link: Some(1) },
+        ]);
+    }
+
+    #[test]
+    fn too_many_links() {
+        let count = u16::MAX as usize + 3;
+        let doc: String = (0..count).map(|i| format!("[a]({}) ", i)).collect();
+        let blocks = parse_markdown(&doc);
+        let text = match blocks[0] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.links.len(), u16::MAX as usize + 1);
+        // every link chunk still points at its own destination
+        let linked: Vec<Option<u16>> = text.chunks.iter()
+            .filter(|chunk| chunk.kind == TextKind::Link)
+            .map(|chunk| chunk.link)
+            .collect();
+        assert_eq!(linked.len(), count);
+        for (i, &link) in linked.iter().enumerate() {
+            let expected = if i <= u16::MAX as usize { Some(i.to_string()) } else { None };
+            assert_eq!(link.map(|link| text.links[link as usize].dest.clone()), expected);
+        }
+    }
+
+    #[test]
+    fn html_images() {
+        let doc = "<p align=\"center\">\n  <img src=\"logo.png\" width=\"200\" alt=\"Logo\">\n</p>\n\n\
+                   Some <img src=\"icon.png\" height=16> text\n";
+        let blocks = parse_markdown(doc);
+        match blocks[0] {
+            Block::Image(ref image) => {
+                assert_eq!((&image.path[..], &image.alt[..], image.width), ("logo.png", "Logo", Some(200)));
+            }
+            ref other => panic!("expected an image, got {:?}", other),
+        }
+        let text = match blocks[1] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.content, "Some \u{fffc} text");
+        assert_eq!(text.images[0].height, Some(16));
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn image_alt_and_title() {
+        let doc = "![a *fancy* diagram](img/d.png \"Figure 1\")\n\nsee ![icon](i.png) here\n";
+        let blocks = parse_markdown(doc);
+        assert_eq!(blocks[0], Block::Image(ImageBlock {
+            path: "img/d.png".to_string(),
+            alt: "a fancy diagram".to_string(),
+            title: "Figure 1".to_string(),
+            width: None,
+            height: None,
+            quote_depth: 0,
+        }));
+        let text = match blocks[1] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.content, "see \u{fffc} here");
+        assert_eq!(text.images[0].alt, "icon");
+        assert!(text.images[0].title.is_empty());
+        assert_eq!(blocks.len(), 2);
+    }
+
+    #[test]
+    fn inline_images_stay_in_order() {
+        let doc = "before\n\nbadges ![a](a.svg)![b](b.svg) inline\n\n![big](big.png)\n\n[![linked](l.png)](https://example.com)\n";
+        let blocks = parse_markdown(doc);
+        assert_eq!(blocks.len(), 4);
+        let text = match blocks[1] {
+            Block::Text(ref text) => text,
+            ref other => panic!("expected text, got {:?}", other),
+        };
+        assert_eq!(text.content, "badges \u{fffc}\u{fffc} inline");
+        assert_eq!(text.chunks, vec![
+            plain(7),
+            Chunk { len: 3, kind: TextKind::InlineImage, link: None },
+            Chunk { len: 3, kind: TextKind::InlineImage, link: None },
+            plain(7),
+        ]);
+        let paths: Vec<&str> = text.images.iter().map(|image| &image.path[..]).collect();
+        assert_eq!(paths, vec!["a.svg", "b.svg"]);
+
+        assert!(matches!(blocks[2], Block::Image(ref image) if image.path == "big.png"));
+        match blocks[3] {
+            Block::Text(ref text) => {
+                assert_eq!(text.chunks, vec![Chunk { len: 3, kind: TextKind::InlineImage, link: Some(0) }]);
+                assert_eq!(text.images.len(), 1);
+            }
+            ref other => panic!("expected text, got {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,54 @@
+use super::*;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct OutlineEntry {
+    pub level: u8,
+    pub text: String,
+    /// Index into the block list the outline was built from
+    pub block: usize,
+}
+
+/// Collects the headings of a document in order, for building a table of
+/// contents or jumping between sections.
+pub fn outline(blocks: &[Block]) -> Vec<OutlineEntry> {
+    blocks.iter().enumerate().filter_map(|(i, block)| {
+        match *block {
+            Block::Text(ref text_block) => text_block.heading_level.map(|level| {
+                OutlineEntry { level, text: text_block.content.trim().to_string(), block: i }
+            }),
+            _ => None,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use markdown::parse_markdown;
+
+    #[test]
+    fn outline_levels() {
+        let doc = "# Title\n\nintro\n\n## Section\n\n### Sub *section*\n\n\
+                   #### Four\n\n##### Five\n\n###### Six\n\ntext\n";
+        let entries: Vec<(u8, String, usize)> = outline(&parse_markdown(doc)).into_iter()
+            .map(|entry| (entry.level, entry.text, entry.block))
+            .collect();
+        assert_eq!(entries, vec![
+            (1, "Title".to_string(), 0),
+            (2, "Section".to_string(), 2),
+            (3, "Sub section".to_string(), 3),
+            (4, "Four".to_string(), 4),
+            (5, "Five".to_string(), 5),
+            (6, "Six".to_string(), 6),
+        ]);
+    }
+
+    #[test]
+    fn headings_without_heading_text() {
+        let doc = "# [Install](install.md)\n\n## `Config`\n\n[not](a.md) a heading\n";
+        let entries: Vec<(u8, String)> = outline(&parse_markdown(doc)).into_iter()
+            .map(|entry| (entry.level, entry.text))
+            .collect();
+        assert_eq!(entries, vec![(1, "Install".to_string()), (2, "Config".to_string())]);
+    }
+}
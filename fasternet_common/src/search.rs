@@ -0,0 +1,174 @@
+use super::*;
+use regex::{self, Regex, RegexBuilder};
+
+/// Where a match is: the block, the cell if the block is a table, and the bytes
+/// of the text it covers
+#[derive(Debug, Hash, PartialEq, Eq, Clone)]
+pub struct Match {
+    pub block: usize,
+    /// Index of the cell counting across the rows, the header is the first row
+    pub cell: Option<usize>,
+    pub range: Range<usize>,
+}
+
+/// Builds the pattern a search looks for. It always ignores case, and
+/// without `regex` the query is looked for as it's written.
+pub fn pattern(query: &str, regex: bool) -> Result<Regex, regex::Error> {
+    let escaped;
+    let source = if regex {
+        query
+    } else {
+        escaped = regex::escape(query);
+        &escaped
+    };
+    RegexBuilder::new(source).case_insensitive(true).build()
+}
+
+/// Every match of `pattern` in the text of the document, in order. Empty
+/// matches are left out, they can't be shown.
+pub fn find_all(blocks: &[Block], pattern: &Regex) -> Vec<Match> {
+    let mut matches = Vec::new();
+    let mut find_in = |block: usize, cell: Option<usize>, text: &TextBlock| {
+        matches.extend(pattern.find_iter(&text.content)
+            .filter(|found| found.start() < found.end())
+            .map(|found| Match { block, cell, range: found.start()..found.end() }));
+    };
+    for (index, block) in blocks.iter().enumerate() {
+        match *block {
+            Block::Text(ref text) => find_in(index, None, text),
+            Block::Table(ref table) => {
+                let cells = table.header.iter().chain(table.rows.iter().flat_map(|row| row.iter()));
+                for (cell, text) in cells.enumerate() {
+                    find_in(index, Some(cell), text);
+                }
+            }
+            Block::Image(_) => (),
+        }
+    }
+    matches
+}
+
+/// A search of a document and which of its matches is the current one
+#[derive(Default)]
+pub struct Search {
+    pub query: String,
+    /// Whether the query is a regular expression rather than plain text
+    pub regex: bool,
+    pub matches: Vec<Match>,
+    pub active: Option<usize>,
+    /// Why the query isn't a valid pattern
+    pub error: Option<String>,
+}
+
+impl Search {
+    /// Finds the matches for the current query again, making the first one
+    /// in or after block `from` current
+    pub fn update(&mut self, blocks: &[Block], from: usize) {
+        self.matches.clear();
+        self.active = None;
+        self.error = None;
+        if self.query.is_empty() {
+            return;
+        }
+        match pattern(&self.query, self.regex) {
+            Ok(pattern) => self.matches = find_all(blocks, &pattern),
+            Err(err) => self.error = Some(err.to_string()),
+        }
+        if !self.matches.is_empty() {
+            let first = self.matches.iter().position(|found| found.block >= from).unwrap_or(0);
+            self.active = Some(first);
+        }
+    }
+
+    pub fn active_match(&self) -> Option<&Match> {
+        self.active.map(|active| &self.matches[active])
+    }
+
+    /// Moves to the next match, or the previous one if `forward` is false,
+    /// wrapping around at either end
+    pub fn step(&mut self, forward: bool) {
+        let len = self.matches.len();
+        if len == 0 {
+            return;
+        }
+        self.active = Some(match self.active {
+            Some(active) if forward => (active + 1) % len,
+            Some(active) => (active + len - 1) % len,
+            None => 0,
+        });
+    }
+
+    /// The matches in block `index`, along with the index in `matches` of the first of them
+    pub fn block_matches(&self, index: usize) -> (usize, &[Match]) {
+        let start = self.matches.partition_point(|found| found.block < index);
+        let end = self.matches.partition_point(|found| found.block <= index);
+        (start, &self.matches[start..end])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use markdown::parse_markdown;
+
+    const DOC: &str = "# Search\n\nFind me, then find *me* again\n\n\
+                       | col | other |\n|---|---|\n| a find | b |\n| c | FIND |\n\n```\nfound(1 + 2)\n```\n";
+
+    fn spans(matches: &[Match]) -> Vec<(usize, Option<usize>, Range<usize>)> {
+        matches.iter().map(|found| (found.block, found.cell, found.range.clone())).collect()
+    }
+
+    #[test]
+    fn ignores_case() {
+        let blocks = parse_markdown(DOC);
+        let found = find_all(&blocks, &pattern("find", false).unwrap());
+        assert_eq!(spans(&found), vec![
+            (1, None, 0..4),
+            (1, None, 14..18),
+            (2, Some(2), 2..6),
+            (2, Some(5), 0..4),
+        ]);
+    }
+
+    #[test]
+    fn literal_and_regex() {
+        let blocks = parse_markdown(DOC);
+        let found = find_all(&blocks, &pattern("1 + 2", false).unwrap());
+        assert_eq!(spans(&found), vec![(3, None, 6..11)]);
+        let found = find_all(&blocks, &pattern(r"f\w+d", true).unwrap());
+        assert_eq!(found.len(), 5);
+        assert_eq!(spans(&found)[4], (3, None, 0..5));
+        // empty matches are everywhere but nothing could be shown for them
+        assert!(find_all(&blocks, &pattern("x*", true).unwrap()).is_empty());
+        assert!(pattern("(unclosed", true).is_err());
+        assert!(pattern("(unclosed", false).is_ok());
+    }
+
+    #[test]
+    fn cycles_through_matches() {
+        let blocks = parse_markdown(DOC);
+        let mut search = Search { query: "FIND".to_string(), ..Search::default() };
+        search.update(&blocks, 2);
+        assert_eq!(search.active, Some(2));
+        search.step(true);
+        search.step(true);
+        assert_eq!(search.active, Some(0));
+        search.step(false);
+        assert_eq!(search.active_match().map(|found| found.cell), Some(Some(5)));
+        assert_eq!(search.block_matches(1), (0, &search.matches[0..2]));
+        assert_eq!(search.block_matches(2).0, 2);
+        assert!(search.block_matches(0).1.is_empty());
+
+        // past the last match it starts over from the top
+        search.update(&blocks, 3);
+        assert_eq!(search.active, Some(0));
+
+        search.query = "[".to_string();
+        search.regex = true;
+        search.update(&blocks, 0);
+        assert!(search.error.is_some());
+        assert_eq!(search.active, None);
+        search.step(true);
+        assert_eq!(search.active, None);
+    }
+}